@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     ops::{Deref, DerefMut},
     path::PathBuf,
     sync::OnceLock,
@@ -7,7 +8,11 @@ use std::{
 use color_eyre::eyre::{Context, Result, eyre};
 use serde_with::{DisplayFromStr, serde_as};
 
-use crate::source::SourceDefinition;
+use crate::{
+    model::Track,
+    source::{AudioFormat, Platform, SourceDefinition, generic::ErrorRule, proxy::Proxy},
+    util::Redacted,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct AppConfig {
@@ -23,6 +28,166 @@ pub struct AppConfig {
     pub sources: Vec<SourceDefinition>,
     pub refresh_cron: Option<Schedule>,
     pub timezone: Option<chrono_tz::Tz>,
+    /// The path to the yt-dlp binary. Defaults to `yt-dlp`, which is resolved
+    /// using `PATH`.
+    #[serde(default = "default_ytdlp_path")]
+    pub ytdlp_path: PathBuf,
+    /// Extra arguments passed to every yt-dlp invocation, before any
+    /// source-specific arguments.
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+    /// Rules used to classify yt-dlp errors for `generic` sources.
+    #[serde(default)]
+    pub error_rules: Vec<ErrorRule>,
+    /// Named credentials that sources can refer to using their `credential`
+    /// option.
+    #[serde(default)]
+    pub credentials: HashMap<String, Credential>,
+    /// Proxies that geo-restricted tracks are retried through, in order.
+    #[serde(default)]
+    pub proxies: Vec<Proxy>,
+    /// The maximum number of tracks that are downloaded at the same time.
+    /// Sources are refreshed one at a time, so this applies to the downloads
+    /// of whichever source is being refreshed.
+    #[serde(default = "default_download_workers")]
+    pub download_workers: usize,
+    /// The maximum number of tracks that are downloaded at the same time from
+    /// each platform. Like `download_workers`, this applies to the source that
+    /// is being refreshed.
+    #[serde(default)]
+    pub platform_workers: HashMap<Platform, usize>,
+    /// The format tracks are saved in.
+    #[serde(default)]
+    pub audio_format: AudioFormat,
+    /// Whether to keep the original stream of every track as an archival
+    /// master next to the playback file.
+    #[serde(default)]
+    pub keep_masters: bool,
+    /// The path to the ffmpeg binary, which is used to regenerate playback
+    /// files from masters.
+    #[serde(default = "default_ffmpeg_path")]
+    pub ffmpeg_path: PathBuf,
+    /// Which changes to a track's manifest mean that the uploader replaced the
+    /// audio of the track, in which case it is downloaded again.
+    #[serde(default)]
+    pub replacement_detection: ReplacementDetection,
+    /// Maps track ID to the version of the track that playlists point at.
+    /// Playlists point at the latest version of tracks that aren't pinned.
+    #[serde(default)]
+    pub pinned_versions: HashMap<String, u32>,
+    /// The number of backups of the index that are kept. A backup is made
+    /// before every refresh.
+    #[serde(default = "default_index_backups")]
+    pub index_backups: usize,
+    /// Where the index is stored.
+    #[serde(default)]
+    pub index_storage: IndexStorage,
+    /// The number of actions after which a refresh saves its progress.
+    #[serde(default = "default_checkpoint_actions")]
+    pub checkpoint_actions: usize,
+    /// The number of seconds after which a refresh saves its progress, if it
+    /// handled any action since it last did.
+    #[serde(default = "default_checkpoint_interval")]
+    pub checkpoint_interval: u64,
+}
+
+/// The formats the index can be stored in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexStorage {
+    /// A single JSON file (`index.json`), which is rewritten every time the
+    /// index is saved
+    #[default]
+    Json,
+    /// An SQLite database (`index.sqlite`), which only the changes are
+    /// written to and which other tools can query
+    Sqlite,
+}
+
+fn default_index_backups() -> usize {
+    5
+}
+
+fn default_checkpoint_actions() -> usize {
+    20
+}
+
+fn default_checkpoint_interval() -> u64 {
+    60
+}
+
+fn default_download_workers() -> usize {
+    1
+}
+
+/// Authentication for a platform, shared between sources.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Credential {
+    /// The path to a Netscape-formatted cookies file. Relative paths are
+    /// resolved against the data folder.
+    pub cookies: Redacted<PathBuf>,
+}
+
+/// The changes to a track's manifest that are treated as the uploader
+/// replacing the audio of the track. Flat listings only contain the title and
+/// duration of tracks (on some platforms), so the full manifest of a track is
+/// fetched again when those changed. File size changes can't be detected in
+/// flat listings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReplacementDetection {
+    /// The duration changed by more than two seconds.
+    pub duration: bool,
+    /// The file size changed by more than 5%.
+    pub filesize: bool,
+    /// The title changed. This is disabled by default, since uploaders often
+    /// rename tracks without changing them.
+    pub title: bool,
+}
+
+impl Default for ReplacementDetection {
+    fn default() -> Self {
+        Self {
+            duration: true,
+            filesize: true,
+            title: false,
+        }
+    }
+}
+
+impl ReplacementDetection {
+    const DURATION_TOLERANCE: f64 = 2.0;
+    const FILESIZE_TOLERANCE: f64 = 0.05;
+
+    /// Whether the audio of `previous` was replaced, going by its new manifest
+    /// `current`. Values that are missing from either manifest are ignored.
+    pub fn is_replaced(&self, previous: &Track, current: &Track) -> bool {
+        let (before, after) = (&previous.metadata, &current.metadata);
+
+        if self.duration
+            && let (Some(before), Some(after)) = (before.duration, after.duration)
+            && (before - after).abs() > Self::DURATION_TOLERANCE
+        {
+            return true;
+        }
+
+        if self.filesize
+            && let (Some(before), Some(after)) = (before.filesize, after.filesize)
+            && (before as f64 - after as f64).abs() > before as f64 * Self::FILESIZE_TOLERANCE
+        {
+            return true;
+        }
+
+        self.title && previous.title != current.title
+    }
+}
+
+fn default_ffmpeg_path() -> PathBuf {
+    PathBuf::from("ffmpeg")
+}
+
+fn default_ytdlp_path() -> PathBuf {
+    PathBuf::from("yt-dlp")
 }
 
 #[serde_as]
@@ -49,6 +214,13 @@ pub struct Paths {
     pub root: PathBuf,
     /// The path to the index file.
     pub index: PathBuf,
+    /// The path to the index database, which is used instead of the index
+    /// file if `AppConfig.index_storage` is `sqlite`.
+    pub database: PathBuf,
+    /// The path to the journal of every transition acad handled.
+    pub journal: PathBuf,
+    /// The directory where backups of the index are stored.
+    pub backups: PathBuf,
     /// The directory where the M3U playlist definitions are stored.
     pub playlists: PathBuf,
     /// The directory where audio files are saved.
@@ -58,12 +230,18 @@ pub struct Paths {
 impl Paths {
     pub fn from_root(data_folder: PathBuf) -> Self {
         let index = data_folder.join("index.json");
+        let database = data_folder.join("index.sqlite");
+        let journal = data_folder.join("journal.jsonl");
+        let backups = data_folder.join("backups");
         let playlists = data_folder.join("playlists");
         let audio = data_folder.join("audio");
 
         Self {
             root: data_folder,
             index,
+            database,
+            journal,
+            backups,
             playlists,
             audio,
         }
@@ -74,6 +252,10 @@ impl Paths {
             std::fs::create_dir_all(&self.root).wrap_err("failed to create data folder")?;
         }
 
+        if !self.backups.exists() {
+            std::fs::create_dir_all(&self.backups).wrap_err("failed to create backups folder")?;
+        }
+
         if !self.playlists.exists() {
             std::fs::create_dir_all(&self.playlists)
                 .wrap_err("failed to create playlists folder")?;
@@ -95,16 +277,32 @@ impl AppConfig {
 
     #[cfg(test)]
     pub fn initialize() {
-        INSTANCE
-            .set(AppConfig {
-                paths: Paths::from_root(PathBuf::from(Self::TEST_DATA_ROOT)),
-                save_thumbnails: false,
-                mpd_music_dir: None,
-                sources: Vec::new(),
-                refresh_cron: None,
-                timezone: None,
-            })
-            .unwrap();
+        INSTANCE.get_or_init(|| AppConfig {
+            paths: Paths::from_root(PathBuf::from(Self::TEST_DATA_ROOT)),
+            save_thumbnails: false,
+            mpd_music_dir: None,
+            sources: Vec::new(),
+            refresh_cron: None,
+            timezone: None,
+            ytdlp_path: default_ytdlp_path(),
+            extra_args: Vec::new(),
+            error_rules: Vec::new(),
+            credentials: HashMap::new(),
+            proxies: Vec::new(),
+            download_workers: default_download_workers(),
+            platform_workers: HashMap::new(),
+            audio_format: AudioFormat::default(),
+            keep_masters: false,
+            ffmpeg_path: default_ffmpeg_path(),
+            replacement_detection: ReplacementDetection::default(),
+            pinned_versions: HashMap::new(),
+            index_backups: default_index_backups(),
+            index_storage: IndexStorage::default(),
+            // small enough for the tests to save progress in the middle of
+            // a source
+            checkpoint_actions: 2,
+            checkpoint_interval: default_checkpoint_interval(),
+        });
     }
 
     pub fn load() -> Result<()> {
@@ -127,6 +325,44 @@ impl AppConfig {
 
         instance.paths = Paths::from_root(data_folder);
 
+        for source in &mut instance.sources {
+            source.url = source.kind.normalize_url(&source.url);
+
+            if let Some(credential) = &source.credential
+                && !instance.credentials.contains_key(credential)
+            {
+                return Err(eyre!(
+                    "source {} refers to an unknown credential: {:?}",
+                    source.url,
+                    credential
+                ));
+            }
+
+            if source.archive_video && source.kind.platform() != Platform::YouTube {
+                return Err(eyre!(
+                    "source {} has `archive_video` enabled, but only YouTube sources have videos",
+                    source.url
+                ));
+            }
+
+            if source.sponsorblock.is_some() && source.kind.platform() != Platform::YouTube {
+                return Err(eyre!(
+                    "source {} has `sponsorblock` enabled, but only YouTube sources have segments",
+                    source.url
+                ));
+            }
+
+            for name in source.proxies.iter().flatten() {
+                if !instance.proxies.iter().any(|proxy| &proxy.name == name) {
+                    return Err(eyre!(
+                        "source {} refers to an unknown proxy: {:?}",
+                        source.url,
+                        name
+                    ));
+                }
+            }
+        }
+
         INSTANCE
             .set(instance)
             .map_err(|_| eyre!("attempted to load config twice"))?;
@@ -0,0 +1,1058 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Context, Result, bail, eyre};
+use rusqlite::{Connection, OpenFlags, Transaction, params};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    config::AppConfig,
+    index::{AppIndex, Changes, IndexedPlaylist, TrackRef, Transition, UnavailableTrack},
+    journal::JournalEntry,
+    migrations,
+    model::PlaylistSection,
+    registry::{Availability, TrackRecord},
+    source::failure::FailureReason,
+};
+
+/// The schema of the database. Every statement is idempotent, so the schema
+/// is applied every time the database is opened.
+///
+/// `tracks` is the track registry. The full manifest of every track is kept in
+/// `tracks.data`; the other manifest columns are copies of the fields that are
+/// useful for querying.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tracks (
+    id TEXT PRIMARY KEY,
+    uploader TEXT NOT NULL,
+    title TEXT NOT NULL,
+    url TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    file_ext TEXT,
+    master_ext TEXT,
+    video_ext TEXT,
+    duration REAL,
+    upload_date TEXT,
+    data TEXT NOT NULL,
+    platform TEXT,
+    first_seen TEXT,
+    last_seen TEXT,
+    downloaded_at TEXT,
+    file_size INTEGER,
+    availability TEXT NOT NULL DEFAULT 'available',
+    availability_reason TEXT
+);
+
+CREATE TABLE IF NOT EXISTS playlists (
+    source_url TEXT PRIMARY KEY,
+    id TEXT NOT NULL,
+    title TEXT NOT NULL,
+    url TEXT NOT NULL,
+    len INTEGER NOT NULL,
+    sections TEXT NOT NULL
+);
+
+-- `state` is one of `present`, `removed`, `deleted` or `restricted`. tracks
+-- can be part of a playlist more than once, so there is no unique key
+CREATE TABLE IF NOT EXISTS memberships (
+    source_url TEXT NOT NULL,
+    track_id TEXT NOT NULL REFERENCES tracks (id),
+    state TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    idx INTEGER NOT NULL,
+    reason TEXT
+);
+
+CREATE INDEX IF NOT EXISTS memberships_source ON memberships (source_url);
+CREATE INDEX IF NOT EXISTS memberships_track ON memberships (track_id);
+
+-- the journal, which is written in the same transaction as the rest of the
+-- index
+CREATE TABLE IF NOT EXISTS transitions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    run_id TEXT,
+    source_url TEXT NOT NULL,
+    track_id TEXT NOT NULL,
+    action TEXT NOT NULL,
+    reason TEXT,
+    time TEXT NOT NULL,
+    uploader TEXT,
+    title TEXT
+);
+
+CREATE TABLE IF NOT EXISTS proxied (
+    track_id TEXT PRIMARY KEY,
+    proxy TEXT NOT NULL
+);
+";
+
+/// The version of [`SCHEMA`], which is stored in the database's
+/// `user_version`. This also covers the format of the manifests in
+/// `tracks.data`, so changing it requires a migration here as well. Migrations
+/// of `index.json` (see [`crate::migrations`]) happen before it is saved to the
+/// database.
+const SCHEMA_VERSION: i64 = 1;
+
+/// A step that migrates the database to the next schema version. Databases
+/// that are created from scratch get the current [`SCHEMA`] instead.
+#[derive(Debug)]
+pub struct SchemaMigration {
+    /// The schema version the database has after this migration
+    pub version: i64,
+    pub description: &'static str,
+    sql: &'static str,
+}
+
+/// Every migration of the database, in the order they are applied. The
+/// schema only gets migrations once a released version of it changes.
+const MIGRATIONS: &[SchemaMigration] = &[];
+
+/// Returns the migrations that have to be applied to a database with the
+/// given schema version. A database without a version is new, so it doesn't
+/// need any.
+pub fn pending(version: i64) -> Result<Vec<&'static SchemaMigration>> {
+    if version > SCHEMA_VERSION {
+        bail!(
+            "the database has schema version {version}, but this version of acad only supports up to {SCHEMA_VERSION}"
+        );
+    }
+
+    if version == 0 {
+        return Ok(Vec::new());
+    }
+
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+fn schema_version(conn: &Connection) -> Result<i64> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+/// The database the index is stored in when `AppConfig.index_storage` is
+/// `sqlite`. This is opened by [`load`].
+static DATABASE: Mutex<Option<Database>> = Mutex::new(None);
+
+/// An SQLite database holding the index.
+#[derive(Debug)]
+pub struct Database {
+    conn: Connection,
+    /// The proxy records as they were last saved, so only the ones that
+    /// changed are written
+    saved_proxied: HashMap<String, String>,
+}
+
+/// The state of a track in a source.
+#[derive(Clone, Copy)]
+enum State {
+    Present,
+    Removed,
+    Deleted,
+    Restricted,
+}
+
+impl State {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Present => "present",
+            Self::Removed => "removed",
+            Self::Deleted => "deleted",
+            Self::Restricted => "restricted",
+        }
+    }
+}
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path).wrap_err("failed to open database")?;
+
+        // the write-ahead log keeps the database readable by other tools
+        // while we write to it
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+
+        for migration in pending(schema_version(&conn)?)? {
+            info!(
+                "migrating database to schema version {}: {}",
+                migration.version, migration.description
+            );
+
+            conn.execute_batch(migration.sql).wrap_err_with(|| {
+                format!(
+                    "failed to migrate database to schema version {}",
+                    migration.version
+                )
+            })?;
+        }
+
+        conn.execute_batch(SCHEMA)
+            .wrap_err("failed to create database schema")?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+
+        Ok(Self {
+            conn,
+            saved_proxied: HashMap::new(),
+        })
+    }
+
+    /// Whether nothing was saved to the database yet.
+    pub fn is_empty(&self) -> Result<bool> {
+        let playlists: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM playlists", [], |row| row.get(0))?;
+        let memberships: i64 =
+            self.conn
+                .query_row("SELECT COUNT(*) FROM memberships", [], |row| row.get(0))?;
+
+        Ok(playlists == 0 && memberships == 0)
+    }
+
+    pub fn load(&mut self) -> Result<AppIndex> {
+        let mut index = AppIndex::default();
+
+        let mut stmt = self.conn.prepare(
+            "SELECT id, data, platform, first_seen, last_seen, downloaded_at, file_size,
+                availability, availability_reason
+            FROM tracks",
+        )?;
+        let tracks = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                [row.get(3)?, row.get(4)?, row.get(5)?],
+                row.get::<_, Option<i64>>(6)?,
+                row.get::<_, String>(7)?,
+                row.get::<_, Option<String>>(8)?,
+            ))
+        })?;
+
+        for track in tracks {
+            let (id, data, platform, times, file_size, availability, reason) = track?;
+
+            let [first_seen, last_seen, downloaded_at] =
+                times.map(|time: Option<String>| time.map(|time| parse_time(&time)).transpose());
+            let reason = reason.map(|reason| parse_name(&reason)).transpose()?;
+
+            let record = TrackRecord {
+                track: serde_json::from_str(&data).wrap_err("failed to parse track")?,
+                platform: platform.map(|platform| parse_name(&platform)).transpose()?,
+                first_seen: first_seen?,
+                last_seen: last_seen?,
+                downloaded_at: downloaded_at?,
+                file_size: file_size.map(|size| size as u64),
+                // the sources are filled in from the memberships
+                sources: BTreeSet::new(),
+                availability: match availability.as_str() {
+                    "available" => Availability::Available,
+                    "deleted" => Availability::Deleted(reason),
+                    "restricted" => Availability::Restricted(reason),
+                    availability => bail!("unknown availability {availability:?}"),
+                },
+            };
+
+            index.tracks.insert(id, record);
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT source_url, id, title, url, len, sections FROM playlists")?;
+        let playlists = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, i64>(4)?,
+                row.get::<_, String>(5)?,
+            ))
+        })?;
+
+        for playlist in playlists {
+            let (source_url, id, title, url, len, sections) = playlist?;
+
+            index.playlists.insert(
+                source_url,
+                IndexedPlaylist {
+                    id,
+                    title,
+                    entries: Vec::new(),
+                    url,
+                    len: len as usize,
+                    sections: serde_json::from_str::<Vec<PlaylistSection>>(&sections)?,
+                },
+            );
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT source_url, track_id, state, idx, reason FROM memberships
+            ORDER BY source_url, state, position",
+        )?;
+        let memberships = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+
+        for membership in memberships {
+            let (source_url, track_id, state, idx, reason) = membership?;
+
+            let record = index
+                .tracks
+                .get_mut(&track_id)
+                .ok_or_else(|| eyre!("track {} is not in the registry", track_id))?;
+
+            if state == "present" {
+                record.sources.insert(source_url.clone());
+            }
+
+            let track = TrackRef {
+                id: track_id,
+                idx: idx as usize,
+            };
+            let reason = reason.map(|reason| parse_name(&reason)).transpose()?;
+
+            match state.as_str() {
+                "present" => index
+                    .playlists
+                    .get_mut(&source_url)
+                    .ok_or_else(|| eyre!("track {} is part of unknown playlist", track.id))?
+                    .entries
+                    .push(track),
+                "removed" => index.removed.entry(source_url).or_default().push(track),
+                "deleted" => index
+                    .deleted
+                    .entry(source_url)
+                    .or_default()
+                    .push(UnavailableTrack { track, reason }),
+                "restricted" => index
+                    .restricted
+                    .entry(source_url)
+                    .or_default()
+                    .push(UnavailableTrack { track, reason }),
+                state => return Err(eyre!("unknown track state {state:?}")),
+            }
+        }
+
+        // every refreshed source has a (possibly empty) list of unavailable
+        // tracks
+        for source_url in index.playlists.keys() {
+            index.deleted.entry(source_url.clone()).or_default();
+            index.removed.entry(source_url.clone()).or_default();
+            index.restricted.entry(source_url.clone()).or_default();
+        }
+
+        let mut stmt = self.conn.prepare("SELECT track_id, proxy FROM proxied")?;
+        for proxied in stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+            let (track_id, proxy) = proxied?;
+            index.proxied.insert(track_id, proxy);
+        }
+
+        self.saved_proxied = index.proxied.clone();
+
+        Ok(index)
+    }
+
+    /// Saves the given changes of the index and the given transitions.
+    pub fn save(
+        &mut self,
+        index: &AppIndex,
+        changes: &Changes,
+        transitions: &[Transition],
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+
+        // tracks are never removed from the registry
+        for id in &changes.tracks {
+            let record = index
+                .tracks
+                .get(id)
+                .ok_or_else(|| eyre!("track {} is not in the registry", id))?;
+
+            save_track(&tx, id, record)?;
+        }
+
+        for url in &changes.sources {
+            trace!("saving source {} to database", url);
+            save_source(&tx, index, url)?;
+        }
+
+        for url in changes.playlists.difference(&changes.sources) {
+            save_playlist(&tx, index, url)?;
+        }
+
+        for (url, id) in &changes.memberships {
+            // the source was written as a whole
+            if !changes.sources.contains(url) {
+                save_membership(&tx, index, url, id)?;
+            }
+        }
+
+        for (track_id, proxy) in &index.proxied {
+            if self.saved_proxied.get(track_id) != Some(proxy) {
+                tx.execute(
+                    "INSERT INTO proxied (track_id, proxy) VALUES (?1, ?2)
+                    ON CONFLICT (track_id) DO UPDATE SET proxy = excluded.proxy",
+                    [track_id, proxy],
+                )?;
+            }
+        }
+        for track_id in self.saved_proxied.keys() {
+            if !index.proxied.contains_key(track_id) {
+                tx.execute("DELETE FROM proxied WHERE track_id = ?1", [track_id])?;
+            }
+        }
+
+        for transition in transitions {
+            tx.execute(
+                "INSERT INTO transitions
+                    (run_id, source_url, track_id, action, reason, time, uploader, title)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    transition.run_id,
+                    transition.source_url,
+                    transition.track_id,
+                    transition.action.name(),
+                    transition.action.reason().map(name_str),
+                    transition.time.to_rfc3339(),
+                    transition.uploader,
+                    transition.title,
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+        self.saved_proxied.clone_from(&index.proxied);
+
+        Ok(())
+    }
+
+    /// Writes a copy of the database to `path`.
+    pub fn backup(&self, path: &Path) -> Result<()> {
+        self.conn.execute(
+            "VACUUM INTO ?1",
+            [path
+                .to_str()
+                .ok_or_else(|| eyre!("backup path is not UTF-8"))?],
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Opens the database and loads the index from it. If nothing was saved to
+/// the database yet, the index is migrated from `index.json`, which is renamed
+/// to `index.migrated.json` afterwards.
+pub fn load() -> Result<AppIndex> {
+    let paths = &AppConfig::get().paths;
+    let mut db = Database::open(&paths.database)?;
+
+    let index = if db.is_empty()? && paths.index.exists() {
+        info!("migrating index.json to the database");
+
+        let index = AppIndex::load_json()?;
+        db.save(&index, &Changes::all(&index), &[])
+            .wrap_err("failed to migrate index to the database")?;
+
+        let migrated = paths.root.join("index.migrated.json");
+        std::fs::rename(&paths.index, &migrated).wrap_err("failed to move migrated index")?;
+
+        info!(
+            "migrated index to the database, the old index was moved to {}",
+            migrated.display()
+        );
+
+        index
+    } else {
+        db.load()
+            .wrap_err("failed to load index from the database")?
+    };
+
+    *DATABASE.lock().unwrap() = Some(db);
+
+    Ok(index)
+}
+
+/// Reports the schema version of the database and the migrations that would be
+/// applied to it the next time it is opened, without changing anything.
+pub fn check() -> Result<()> {
+    let paths = &AppConfig::get().paths;
+
+    let empty = if paths.database.exists() {
+        let conn = Connection::open_with_flags(&paths.database, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .wrap_err("failed to open database")?;
+        let version = schema_version(&conn)?;
+
+        println!(
+            "{} has schema version {} (the current version is {})",
+            paths.database.display(),
+            version,
+            SCHEMA_VERSION
+        );
+
+        let pending = pending(version)?;
+
+        if pending.is_empty() {
+            println!("no database migrations need to run");
+        }
+
+        for migration in pending {
+            println!(
+                "would migrate the database to version {}: {}",
+                migration.version, migration.description
+            );
+        }
+
+        version == 0
+            || conn.query_row(
+                "SELECT NOT EXISTS (SELECT 1 FROM playlists)
+                    AND NOT EXISTS (SELECT 1 FROM memberships)",
+                [],
+                |row| row.get(0),
+            )?
+    } else {
+        println!("there is no database at {}", paths.database.display());
+        true
+    };
+
+    if empty && paths.index.exists() {
+        println!(
+            "{} would be migrated into the database",
+            paths.index.display()
+        );
+        return migrations::check_json(&paths.index);
+    }
+
+    Ok(())
+}
+
+/// Reads every transition in the database, oldest first, without changing
+/// anything. This is the journal when the index is stored in the database.
+pub fn read_journal() -> Result<Vec<JournalEntry>> {
+    let path = &AppConfig::get().paths.database;
+
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .wrap_err("failed to open database")?;
+
+    read_transitions(&conn)
+}
+
+fn read_transitions(conn: &Connection) -> Result<Vec<JournalEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT time, run_id, source_url, track_id, uploader, title, action, reason
+        FROM transitions ORDER BY id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, Option<String>>(5)?,
+            row.get::<_, String>(6)?,
+            row.get::<_, Option<String>>(7)?,
+        ))
+    })?;
+
+    rows.map(|row| {
+        let (time, run_id, source_url, track_id, uploader, title, action, reason) = row?;
+
+        Ok(JournalEntry {
+            time: parse_time(&time)?,
+            run_id: run_id.unwrap_or_default(),
+            source_url,
+            track_id,
+            uploader: uploader.unwrap_or_default(),
+            title: title.unwrap_or_default(),
+            action,
+            reason: reason.map(|reason| parse_name(&reason)).transpose()?,
+        })
+    })
+    .collect()
+}
+
+/// Saves the changes of the index to the database opened by [`load`].
+pub fn save(index: &AppIndex, changes: &Changes, transitions: &[Transition]) -> Result<()> {
+    DATABASE
+        .lock()
+        .unwrap()
+        .as_mut()
+        .ok_or_else(|| eyre!("the database was not loaded"))?
+        .save(index, changes, transitions)
+}
+
+/// Writes a copy of the database opened by [`load`] to `path`.
+pub fn backup(path: &Path) -> Result<()> {
+    DATABASE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .ok_or_else(|| eyre!("the database was not loaded"))?
+        .backup(path)
+}
+
+/// Replaces the playlist and every membership of a source in the database.
+/// The tracks themselves are saved from the registry.
+fn save_source(tx: &Transaction, index: &AppIndex, url: &str) -> Result<()> {
+    tx.execute("DELETE FROM memberships WHERE source_url = ?1", [url])?;
+
+    save_playlist(tx, index, url)?;
+
+    let mut positions = HashMap::new();
+
+    for (state, track, reason) in memberships(index, url) {
+        let position = positions.entry(state.as_str()).or_insert(0i64);
+        insert_membership(tx, url, state, *position, track, reason)?;
+        *position += 1;
+    }
+
+    Ok(())
+}
+
+/// Replaces the memberships of a single track in a source. The track was just
+/// added to the end of the list of its new state, so it is placed after every
+/// other track in that state.
+fn save_membership(tx: &Transaction, index: &AppIndex, url: &str, id: &str) -> Result<()> {
+    tx.execute(
+        "DELETE FROM memberships WHERE source_url = ?1 AND track_id = ?2",
+        [url, id],
+    )?;
+
+    for (state, track, reason) in memberships(index, url).filter(|(_, t, _)| t.id == id) {
+        let position: i64 = tx.query_row(
+            "SELECT COALESCE(MAX(position) + 1, 0) FROM memberships
+            WHERE source_url = ?1 AND state = ?2",
+            [url, state.as_str()],
+            |row| row.get(0),
+        )?;
+
+        insert_membership(tx, url, state, position, track, reason)?;
+    }
+
+    Ok(())
+}
+
+fn insert_membership(
+    tx: &Transaction,
+    url: &str,
+    state: State,
+    position: i64,
+    track: &TrackRef,
+    reason: Option<FailureReason>,
+) -> Result<()> {
+    tx.execute(
+        "INSERT INTO memberships (source_url, track_id, state, position, idx, reason)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            url,
+            track.id,
+            state.as_str(),
+            position,
+            track.idx as i64,
+            reason.map(name_str),
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Writes (or removes) the row of a source's playlist.
+fn save_playlist(tx: &Transaction, index: &AppIndex, url: &str) -> Result<()> {
+    match index.playlists.get(url) {
+        Some(playlist) => {
+            tx.execute(
+                "INSERT INTO playlists (source_url, id, title, url, len, sections)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT (source_url) DO UPDATE SET
+                    id = excluded.id,
+                    title = excluded.title,
+                    url = excluded.url,
+                    len = excluded.len,
+                    sections = excluded.sections",
+                params![
+                    url,
+                    playlist.id,
+                    playlist.title,
+                    playlist.url,
+                    playlist.len as i64,
+                    serde_json::to_string(&playlist.sections)?,
+                ],
+            )?;
+        }
+        None => {
+            tx.execute("DELETE FROM playlists WHERE source_url = ?1", [url])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Every track that is part of a source, in the order they are listed in
+/// each state.
+fn memberships<'a>(
+    index: &'a AppIndex,
+    url: &str,
+) -> impl Iterator<Item = (State, &'a TrackRef, Option<FailureReason>)> {
+    let present = index
+        .playlists
+        .get(url)
+        .into_iter()
+        .flat_map(|pl| &pl.entries)
+        .map(|t| (State::Present, t, None));
+    let removed = index
+        .removed
+        .get(url)
+        .into_iter()
+        .flatten()
+        .map(|t| (State::Removed, t, None));
+    let deleted = index
+        .deleted
+        .get(url)
+        .into_iter()
+        .flatten()
+        .map(|u| (State::Deleted, &u.track, u.reason));
+    let restricted = index
+        .restricted
+        .get(url)
+        .into_iter()
+        .flatten()
+        .map(|u| (State::Restricted, &u.track, u.reason));
+
+    present.chain(removed).chain(deleted).chain(restricted)
+}
+
+fn save_track(tx: &Transaction, id: &str, record: &TrackRecord) -> Result<()> {
+    let track = &record.track;
+
+    tx.execute(
+        "INSERT INTO tracks (
+            id, uploader, title, url, version, file_ext, master_ext, video_ext, duration,
+            upload_date, data, platform, first_seen, last_seen, downloaded_at, file_size,
+            availability, availability_reason
+        )
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)
+        ON CONFLICT (id) DO UPDATE SET
+            uploader = excluded.uploader,
+            title = excluded.title,
+            url = excluded.url,
+            version = excluded.version,
+            file_ext = excluded.file_ext,
+            master_ext = excluded.master_ext,
+            video_ext = excluded.video_ext,
+            duration = excluded.duration,
+            upload_date = excluded.upload_date,
+            data = excluded.data,
+            platform = excluded.platform,
+            first_seen = excluded.first_seen,
+            last_seen = excluded.last_seen,
+            downloaded_at = excluded.downloaded_at,
+            file_size = excluded.file_size,
+            availability = excluded.availability,
+            availability_reason = excluded.availability_reason",
+        params![
+            id,
+            track.uploader,
+            track.title,
+            track.url,
+            track.version,
+            track.file_ext,
+            track.master_ext,
+            track.video_ext,
+            track.metadata.duration,
+            track.metadata.upload_date,
+            serde_json::to_string(track)?,
+            record.platform.map(name_str),
+            record.first_seen.map(|time| time.to_rfc3339()),
+            record.last_seen.map(|time| time.to_rfc3339()),
+            record.downloaded_at.map(|time| time.to_rfc3339()),
+            record.file_size.map(|size| size as i64),
+            record.availability.status(),
+            record.availability.reason().map(name_str),
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// The name a failure reason or platform is stored as, e.g.
+/// `removed_by_uploader`.
+fn name_str<T: Serialize>(value: T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn parse_name<T: DeserializeOwned>(name: &str) -> Result<T> {
+    serde_json::from_value(serde_json::Value::String(name.to_string()))
+        .wrap_err_with(|| format!("unknown value {name:?}"))
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(time)
+        .wrap_err_with(|| format!("invalid time {time:?}"))?
+        .with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::{
+        index::Action,
+        model::{Playlist, Track},
+        source::{Platform, SourceDefinition, scripted::ScriptedFetcher},
+    };
+
+    fn track(id: &str, idx: usize) -> Track {
+        Track {
+            id: id.to_string(),
+            uploader: "uploader".to_string(),
+            title: format!("track {id}"),
+            url: format!("https://example.com/{id}"),
+            idx,
+            file_ext: Some("mp3".to_string()),
+            master_ext: None,
+            video_ext: None,
+            sponsor_segments: None,
+            version: 1,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        AppConfig::initialize();
+        AppConfig::get().paths.ensure_all().unwrap();
+
+        let path = AppConfig::get().paths.root.join("database-test.sqlite");
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+
+        let url = "https://example.com/playlist".to_string();
+        let mut index = AppIndex::default();
+
+        // the tracks that left the source were recorded when they did
+        let (c, d) = (track("c", 4), track("d", 5));
+        for track in [&c, &d] {
+            index
+                .tracks
+                .insert(track.id.clone(), TrackRecord::new(track));
+        }
+
+        index.removed.insert(url.clone(), vec![TrackRef::from(&c)]);
+        index.deleted.insert(
+            url.clone(),
+            vec![UnavailableTrack {
+                track: TrackRef::from(&d),
+                reason: Some(FailureReason::RemovedByUploader),
+            }],
+        );
+        index.restricted.insert(url.clone(), Vec::new());
+        index.proxied.insert("b".to_string(), "proxy".to_string());
+        index.sync_registry(
+            &url,
+            Playlist {
+                id: "playlist".to_string(),
+                title: "playlist".to_string(),
+                entries: vec![track("a", 1), track("b", 2), track("a", 3)],
+                url: url.clone(),
+                len: 3,
+                sections: Vec::new(),
+            },
+            Some(Platform::Generic),
+            Some(Utc::now()),
+        );
+
+        let transitions = [Transition {
+            run_id: "run".to_string(),
+            source_url: url.clone(),
+            track_id: "d".to_string(),
+            uploader: "uploader".to_string(),
+            title: "track d".to_string(),
+            action: Action::Deleted(FailureReason::RemovedByUploader),
+            time: Utc::now(),
+        }];
+
+        let mut db = Database::open(&path).unwrap();
+        assert!(db.is_empty().unwrap());
+        db.save(&index, &Changes::all(&index), &transitions)
+            .unwrap();
+
+        let loaded = Database::open(&path).unwrap().load().unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&index).unwrap()
+        );
+
+        // the library can be queried with plain SQL
+        let count = |sql: &str| -> i64 { db.conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM tracks"), 4);
+        assert_eq!(
+            count("SELECT COUNT(*) FROM memberships WHERE state = 'present'"),
+            3
+        );
+        assert_eq!(
+            count("SELECT COUNT(*) FROM transitions WHERE reason = 'removed_by_uploader'"),
+            1
+        );
+        assert_eq!(
+            count("SELECT COUNT(*) FROM tracks WHERE availability = 'deleted'"),
+            1
+        );
+
+        // the transitions are the journal
+        let journal = read_transitions(&db.conn).unwrap();
+        assert_eq!(journal.len(), 1);
+        assert_eq!(journal[0].track_id, "d");
+        assert_eq!(journal[0].title, "track d");
+        assert_eq!(journal[0].reason, Some(FailureReason::RemovedByUploader));
+        assert_eq!(
+            loaded.tracks["a"].sources.iter().collect::<Vec<_>>(),
+            [&url]
+        );
+
+        // only the recorded changes are saved
+        index.removed.get_mut(&url).unwrap().clear();
+        index.proxied.clear();
+        index.tracks.get_mut("a").unwrap().track.title = "unsaved".to_string();
+        let changes = Changes {
+            memberships: [(url.clone(), "c".to_string())].into(),
+            ..Default::default()
+        };
+        db.save(&index, &changes, &[]).unwrap();
+
+        let loaded = Database::open(&path).unwrap().load().unwrap();
+        assert!(loaded.removed[&url].is_empty());
+        assert!(loaded.proxied.is_empty());
+        assert_eq!(loaded.playlists[&url].entries.len(), 3);
+        assert_eq!(loaded.tracks["a"].track.title, "track a");
+    }
+
+    #[test]
+    fn test_migrate() {
+        AppConfig::initialize();
+        AppConfig::get().paths.ensure_all().unwrap();
+
+        let path = AppConfig::get()
+            .paths
+            .root
+            .join("database-migrate-test.sqlite");
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+
+        // new databases get the current schema
+        assert!(pending(0).unwrap().is_empty());
+        let db = Database::open(&path).unwrap();
+        assert_eq!(schema_version(&db.conn).unwrap(), SCHEMA_VERSION);
+        assert!(pending(SCHEMA_VERSION).unwrap().is_empty());
+        drop(db);
+
+        // opening the database again leaves it as it was
+        let db = Database::open(&path).unwrap();
+        assert_eq!(schema_version(&db.conn).unwrap(), SCHEMA_VERSION);
+        drop(db);
+
+        // databases written by a newer version of acad are refused
+        Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(pending(SCHEMA_VERSION + 1).is_err());
+        assert!(Database::open(&path).is_err());
+    }
+
+    #[test]
+    fn test_refresh_saves_changes() {
+        AppConfig::initialize();
+        AppConfig::get().paths.ensure_all().unwrap();
+
+        let path = AppConfig::get()
+            .paths
+            .root
+            .join("database-refresh-test.sqlite");
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+
+        let source: SourceDefinition = serde_json::from_str(
+            r#"{ "type": "generic", "url": "https://example.com/database-refresh" }"#,
+        )
+        .unwrap();
+        let url = source.url.clone();
+        let sources = [source];
+
+        let [a, b, c, d] = ["dbr-a", "dbr-b", "dbr-c", "dbr-d"].map(|id| Track {
+            file_ext: None,
+            ..track(id, 0)
+        });
+        for track in [&a, &b, &c, &d] {
+            let _ = std::fs::remove_dir_all(track.as_handle().root_dir);
+        }
+
+        let fetcher = ScriptedFetcher::default();
+        let mut index = AppIndex::default();
+        let mut db = Database::open(&path).unwrap();
+
+        // the database lists every source's (possibly empty) unavailable
+        // tracks, which the index only does once the source was refreshed
+        let value = |index: &AppIndex| {
+            let mut value = serde_json::to_value(index).unwrap();
+            for list in ["deleted", "removed", "restricted"] {
+                value[list]
+                    .as_object_mut()
+                    .unwrap()
+                    .retain(|_, tracks| tracks.as_array().is_some_and(|t| !t.is_empty()));
+            }
+            value
+        };
+
+        // after saving the changes at any checkpoint, the database holds the
+        // same index as the one in memory
+        let mut refresh = |index: &mut AppIndex| {
+            index
+                .refresh_with(&sources, &fetcher, |index| {
+                    let changes = std::mem::take(&mut index.changes);
+                    let transitions = std::mem::take(&mut index.transitions);
+                    db.save(index, &changes, &transitions)?;
+
+                    let loaded = Database::open(&path)?.load()?;
+                    assert_eq!(value(&loaded), value(index));
+
+                    Ok(())
+                })
+                .unwrap();
+        };
+
+        fetcher.set_playlist(&url, &[&a, &b, &c]);
+        refresh(&mut index);
+
+        fetcher.set_playlist(&url, &[&d, &a, &c]);
+        fetcher.set_unavailable(&b.id, FailureReason::NotFound);
+        refresh(&mut index);
+
+        fetcher.set_playlist(&url, &[&a, &c]);
+        refresh(&mut index);
+
+        fetcher.set_playlist(&url, &[&b, &a, &c]);
+        fetcher.set_available(&b.id);
+        refresh(&mut index);
+
+        assert_eq!(index.deleted[&url].len(), 0);
+        assert_eq!(index.removed[&url].len(), 1);
+    }
+}
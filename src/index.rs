@@ -1,27 +1,195 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    io::Write,
+    iter,
+    path::{Path, PathBuf},
     sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
+use chrono::{DateTime, Utc};
 use color_eyre::eyre::{Context, Result};
 use id3::{TagLike, frame};
+use serde::{Deserialize, Serialize, Serializer};
 
 use crate::{
-    config::AppConfig,
+    config::{AppConfig, IndexStorage},
+    database, journal, lyrics,
     m3u::write_playlist,
-    model::{Playlist, Track},
+    migrations::{self, NewerSchema},
+    model::{DEFAULT_FILE_EXT, Playlist, PlaylistHandle, PlaylistSection, Track, TrackHandle},
+    registry::{Availability, TrackRecord},
     retry::retry_with,
-    source::{Fetcher, SourceType, TrackDownloadStatus, TrackStatus},
-    util,
+    source::{
+        Fetcher, Platform, SourceDefinition, SourceFetcher, TrackDownloadStatus, TrackStatus,
+        failure::{FailureKind, FailureReason},
+        proxy::with_proxy_fallback,
+    },
+    sponsorblock, tags, util,
 };
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct AppIndex {
+    /// Maps track ID to everything the index knows about the track. The
+    /// playlists and lists of unavailable tracks only refer to these, so the
+    /// manifest of a track is only kept once
+    #[serde(serialize_with = "sorted")]
+    pub tracks: HashMap<String, TrackRecord>,
     /// Maps playlist URL to playlist
-    pub playlists: HashMap<String, Playlist>,
-    pub deleted: HashMap<String, Vec<Track>>,
-    pub removed: HashMap<String, Vec<Track>>,
-    pub restricted: HashMap<String, Vec<Track>>,
+    pub playlists: HashMap<String, IndexedPlaylist>,
+    pub deleted: HashMap<String, Vec<UnavailableTrack>>,
+    pub removed: HashMap<String, Vec<TrackRef>>,
+    pub restricted: HashMap<String, Vec<UnavailableTrack>>,
+    /// Maps track ID to the name of the proxy the track was last reachable
+    /// through
+    #[serde(default)]
+    pub proxied: HashMap<String, String>,
+    /// The transitions that happened since the index was last saved
+    #[serde(skip)]
+    pub transitions: Vec<Transition>,
+    /// What changed since the index was last saved
+    #[serde(skip)]
+    pub changes: Changes,
+    /// The ID of the refresh that is running, which is the time it started
+    #[serde(skip)]
+    pub run_id: String,
+}
+
+/// Saves the registry sorted by track ID, so saving the index doesn't
+/// shuffle it.
+fn sorted<S: Serializer>(
+    tracks: &HashMap<String, TrackRecord>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    tracks
+        .iter()
+        .collect::<BTreeMap<_, _>>()
+        .serialize(serializer)
+}
+
+/// A playlist as the index keeps it, whose entries refer to the registry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedPlaylist {
+    pub id: String,
+    pub title: String,
+    pub entries: Vec<TrackRef>,
+    #[serde(rename = "original_url")]
+    pub url: String,
+    #[serde(rename = "playlist_count")]
+    pub len: usize,
+    #[serde(default)]
+    pub sections: Vec<PlaylistSection>,
+}
+
+impl IndexedPlaylist {
+    pub fn as_handle(&self) -> PlaylistHandle {
+        PlaylistHandle::from_id(&self.id)
+    }
+}
+
+impl From<Playlist> for IndexedPlaylist {
+    fn from(playlist: Playlist) -> Self {
+        Self {
+            id: playlist.id,
+            title: playlist.title,
+            entries: playlist.entries.iter().map(TrackRef::from).collect(),
+            url: playlist.url,
+            len: playlist.len,
+            sections: playlist.sections,
+        }
+    }
+}
+
+/// A reference to a track in the registry, at a position in a playlist.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackRef {
+    pub id: String,
+    #[serde(rename = "playlist_index")]
+    pub idx: usize,
+}
+
+impl TrackRef {
+    /// Returns the latest manifest of the track as an entry of the playlist,
+    /// or `None` if the track is not in the registry.
+    pub fn resolve(&self, registry: &HashMap<String, TrackRecord>) -> Option<Track> {
+        registry.get(&self.id).map(|record| record.entry(self.idx))
+    }
+}
+
+impl From<&Track> for TrackRef {
+    fn from(track: &Track) -> Self {
+        Self {
+            id: track.id.clone(),
+            idx: track.idx,
+        }
+    }
+}
+
+/// A change in the state of a track that was handled during a refresh.
+#[derive(Debug, Clone)]
+pub struct Transition {
+    /// The ID of the refresh the transition was handled in
+    pub run_id: String,
+    /// The URL of the source the track is part of
+    pub source_url: String,
+    pub track_id: String,
+    pub uploader: String,
+    pub title: String,
+    pub action: Action,
+    pub time: DateTime<Utc>,
+}
+
+/// What changed in the index since it was last saved, so the database only
+/// writes the rows that changed. The JSON index is always written as a whole.
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// The IDs of the tracks whose record changed
+    pub tracks: BTreeSet<String>,
+    /// The URLs of the sources whose playlist (but not its tracks) changed
+    pub playlists: BTreeSet<String>,
+    /// The tracks whose state in a source changed, as (source URL, track ID)
+    pub memberships: BTreeSet<(String, String)>,
+    /// The URLs of the sources whose whole state was replaced
+    pub sources: BTreeSet<String>,
+}
+
+impl Changes {
+    /// Every track and source in the index, for when it is written to an
+    /// empty database.
+    pub fn all(index: &AppIndex) -> Self {
+        Self {
+            tracks: index.tracks.keys().cloned().collect(),
+            sources: index
+                .playlists
+                .keys()
+                .chain(index.deleted.keys())
+                .chain(index.removed.keys())
+                .chain(index.restricted.keys())
+                .cloned()
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Adds the changes of `other`, which were not saved.
+    pub fn extend(&mut self, other: Self) {
+        self.tracks.extend(other.tracks);
+        self.playlists.extend(other.playlists);
+        self.memberships.extend(other.memberships);
+        self.sources.extend(other.sources);
+    }
+}
+
+/// A track that was deleted or restricted, along with the reason why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnavailableTrack {
+    #[serde(flatten)]
+    pub track: TrackRef,
+    /// This is `None` for tracks that became unavailable before reasons were
+    /// recorded in the index
+    #[serde(default)]
+    pub reason: Option<FailureReason>,
 }
 
 pub struct TrackAction<'a> {
@@ -36,6 +204,12 @@ macro_rules! act {
             action: Action::$action,
         }
     };
+    ($track:ident = $action:ident($reason:expr)) => {
+        TrackAction {
+            track: $track,
+            action: Action::$action($reason),
+        }
+    };
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -49,23 +223,49 @@ pub enum Action {
     Unremoved,
 
     /// This track was deleted
-    Deleted,
+    Deleted(FailureReason),
     /// This track had been deleted but was added back
     Undeleted,
 
     /// This track was restricted (private, geo-restricted, etc.)
-    Restricted,
+    Restricted(FailureReason),
     /// This track had been restricted but is not anymore
     Unrestricted,
+
+    /// The uploader replaced the audio of this track, so it is downloaded
+    /// again as a new version
+    Replaced,
 }
 
 impl Action {
+    /// The name of the action, e.g. `deleted`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Added => "added",
+            Self::Removed => "removed",
+            Self::Unremoved => "unremoved",
+            Self::Deleted(_) => "deleted",
+            Self::Undeleted => "undeleted",
+            Self::Restricted(_) => "restricted",
+            Self::Unrestricted => "unrestricted",
+            Self::Replaced => "replaced",
+        }
+    }
+
+    /// The reason the track became unavailable, if it did.
+    pub fn reason(&self) -> Option<FailureReason> {
+        match self {
+            Self::Deleted(reason) | Self::Restricted(reason) => Some(*reason),
+            _ => None,
+        }
+    }
+
     pub fn necessary_operations(&self) -> Vec<Operation> {
         use Action::*;
         use Operation as O;
 
         match self {
-            Added => vec![O::Download],
+            Added | Replaced => vec![O::Download, O::WriteTags, O::EmbedLyrics],
             _ => vec![O::AddMetadataMarker(*self)],
         }
     }
@@ -77,18 +277,35 @@ pub enum Operation {
     AddMetadataMarker(Action),
     /// Download the track
     Download,
+    /// Write the metadata from the track's manifest to its tags
+    WriteTags,
+    /// Embed the lyrics saved next to the track into its metadata
+    EmbedLyrics,
 }
 
 impl Operation {
-    #[instrument(skip(self, playlist))]
-    pub fn perform(
+    #[instrument(skip(self, fetcher, source, playlist, proxied))]
+    pub fn perform<F: Fetcher>(
         self,
-        source_type: SourceType,
+        fetcher: &F,
+        source: &SourceDefinition,
         track: &Track,
         playlist: &Playlist,
+        proxied: &mut HashMap<String, String>,
     ) -> Result<()> {
         match self {
-            Self::Download => match source_type.ensure_track_downloaded(track)? {
+            Self::Download => match with_proxy_fallback(
+                source,
+                &fetcher.proxies(source),
+                &track.id,
+                proxied,
+                |source| fetcher.ensure_track_downloaded(source, track),
+                |result| {
+                    result.as_ref().is_err_and(|err| {
+                        FailureReason::classify_error(err) == Some(FailureReason::GeoBlocked)
+                    })
+                },
+            )? {
                 TrackDownloadStatus::Downloaded => {
                     info!("downloaded track");
                 }
@@ -96,38 +313,78 @@ impl Operation {
                     debug!("track was already downloaded so we did not download it again");
                 }
             },
+            Self::WriteTags => {
+                let Some(handle) = downloaded_mp3(track) else {
+                    debug!("not writing tags, they are only supported for MP3 files");
+                    return Ok(());
+                };
+
+                tags::write_tags(&handle, track)?;
+            }
+            Self::EmbedLyrics => {
+                if source.lyrics.is_none() {
+                    return Ok(());
+                }
+
+                let Some(handle) = downloaded_mp3(track) else {
+                    debug!("not embedding lyrics, they are only supported for MP3 files");
+                    return Ok(());
+                };
+
+                let lyrics = lyrics::read_saved(&handle)?;
+
+                if lyrics.is_empty() {
+                    debug!("no lyrics were found");
+                    return Ok(());
+                }
+
+                trace!("embedding {} lyrics", lyrics.len());
+                lyrics::embed(&handle, &lyrics)?;
+            }
             Self::AddMetadataMarker(state) => {
                 trace!("adding metadata marker for {:?}", state);
 
                 let handle = track.as_handle();
 
-                let mut metadata = id3::Tag::read_from_path(&handle.track_path)
-                    .wrap_err("failed to read track metadata to write new state to")?;
-
                 let msg = format!(
                     "This track was {}. {} ({})\n",
                     match state {
-                        Action::Added => "added to the playlist",
-                        Action::Removed => "removed from the playlist",
-                        Action::Unremoved => "added back to the playlist",
-                        Action::Deleted => "deleted",
-                        Action::Undeleted => "added back",
-                        Action::Restricted => "restricted (private, geo-restricted, etc.)",
-                        Action::Unrestricted => "no longer restricted",
+                        Action::Added => "added to the playlist".to_string(),
+                        Action::Removed => "removed from the playlist".to_string(),
+                        Action::Unremoved => "added back to the playlist".to_string(),
+                        Action::Deleted(reason) => format!("deleted ({reason})"),
+                        Action::Undeleted => "added back".to_string(),
+                        Action::Restricted(reason) => format!("restricted ({reason})"),
+                        Action::Unrestricted => "no longer restricted".to_string(),
+                        Action::Replaced => "replaced by the uploader".to_string(),
                     },
                     playlist.title,
                     playlist.id,
                 );
 
-                metadata.add_frame(frame::Comment {
-                    lang: "en".to_owned(),
-                    text: msg.clone(),
-                    description: msg,
-                });
+                // markers are embedded as ID3 comments in MP3 files, and saved
+                // next to the track whatever its format
+                if track.file_ext.as_deref().unwrap_or(DEFAULT_FILE_EXT) == "mp3" {
+                    let mut metadata = id3::Tag::read_from_path(&handle.track_path)
+                        .wrap_err("failed to read track metadata to write new state to")?;
+
+                    metadata.add_frame(frame::Comment {
+                        lang: "en".to_owned(),
+                        text: msg.clone(),
+                        description: msg.clone(),
+                    });
 
-                metadata
-                    .write_to_path(&handle.track_path, id3::Version::Id3v24)
-                    .wrap_err("failed to write track metadata")?;
+                    metadata
+                        .write_to_path(&handle.track_path, id3::Version::Id3v24)
+                        .wrap_err("failed to write track metadata")?;
+                }
+
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(handle.root_dir.join("markers.txt"))
+                    .and_then(|mut markers| markers.write_all(msg.as_bytes()))
+                    .wrap_err("failed to save track marker")?;
             }
         }
 
@@ -135,32 +392,225 @@ impl Operation {
     }
 }
 
+/// Returns the handle of a track that was just downloaded (so its extension
+/// isn't recorded yet) if it was downloaded as an MP3, which is the only format
+/// we can write ID3 frames to.
+fn downloaded_mp3(track: &Track) -> Option<TrackHandle> {
+    let handle = track.as_handle();
+
+    if handle.find_file_ext().as_deref() != Some("mp3") {
+        return None;
+    }
+
+    Some(TrackHandle {
+        track_path: handle.root_dir.join("track.mp3"),
+        ..handle
+    })
+}
+
 fn cmp_ids(t1: &Track, t2: &Track) -> bool {
     t1.id == t2.id
 }
 
 static IS_REFRESHING: AtomicBool = AtomicBool::new(false);
 
+/// Marks a refresh as running until it is dropped, so the flag is cleared
+/// however the refresh ends.
+struct RefreshGuard;
+
+impl RefreshGuard {
+    fn start() -> Self {
+        IS_REFRESHING.store(true, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for RefreshGuard {
+    fn drop(&mut self) {
+        IS_REFRESHING.store(false, Ordering::Relaxed);
+    }
+}
+
+/// The actions a refresh handled since it last saved its progress. Progress
+/// is saved once `checkpoint_actions` actions were handled or
+/// `checkpoint_interval` seconds passed, since rewriting the whole index after
+/// every action is slow for large indexes.
+struct Progress {
+    actions: usize,
+    since: Instant,
+}
+
+impl Progress {
+    fn new() -> Self {
+        Self {
+            actions: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Records a handled action, returning `true` if the progress should be
+    /// saved now (in which case it starts over).
+    fn record(&mut self) -> bool {
+        let config = AppConfig::get();
+        self.actions += 1;
+
+        let due = self.actions >= config.checkpoint_actions
+            || self.since.elapsed() >= Duration::from_secs(config.checkpoint_interval);
+
+        if due {
+            *self = Self::new();
+        }
+
+        due
+    }
+}
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
 impl AppIndex {
     pub fn load() -> Result<Self> {
+        match AppConfig::get().index_storage {
+            IndexStorage::Json => Self::load_json(),
+            IndexStorage::Sqlite => database::load(),
+        }
+    }
+
+    /// Loads the index from the index file. If the index file is corrupt, it
+    /// is moved out of the way and the newest backup that can be read is
+    /// loaded instead.
+    pub fn load_json() -> Result<Self> {
         trace!("loading index");
         let path = &AppConfig::get().paths.index;
 
-        if path.exists() {
-            trace!("index file exists, loading it");
-            let index = std::fs::read_to_string(path)?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        trace!("index file exists, loading it");
+        let err = match Self::read(path) {
+            Ok(index) => return Ok(index),
+            // the index isn't corrupt, so we must not replace it with a backup
+            Err(err) if err.downcast_ref::<NewerSchema>().is_some() => return Err(err),
+            Err(err) => err,
+        };
 
-            Ok(serde_json::from_str(&index)?)
-        } else {
-            Ok(Self::default())
+        error!("failed to load index, trying backups: {:?}", err);
+
+        for backup in list_backups("json")?.into_iter().rev() {
+            match Self::read(&backup) {
+                Ok(index) => {
+                    // the corrupt index is kept for inspection, but moved so
+                    // it doesn't get backed up over the valid backups
+                    let corrupt = AppConfig::get()
+                        .paths
+                        .root
+                        .join(format!("index.corrupt-{}.json", backup_timestamp()));
+                    std::fs::rename(path, &corrupt)
+                        .wrap_err("failed to move corrupt index out of the way")?;
+
+                    warn!(
+                        "recovered index from {}, the corrupt index was moved to {}",
+                        backup.display(),
+                        corrupt.display()
+                    );
+
+                    return Ok(index);
+                }
+                Err(err) => warn!("backup {} is not valid: {:?}", backup.display(), err),
+            }
+        }
+
+        Err(err).wrap_err("index is corrupt and there is no valid backup")
+    }
+
+    /// Reads an index file, migrating it to the current schema version if
+    /// necessary. The file is backed up before it is migrated, but the
+    /// migrated index is only written the next time the index is saved.
+    fn read(path: &Path) -> Result<Self> {
+        let mut index = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+
+        let version = migrations::schema_version(&index);
+
+        if !migrations::pending(version)?.is_empty() {
+            let backup = AppConfig::get().paths.backups.join(format!(
+                "index.schema-v{}-{}.json",
+                version,
+                backup_timestamp()
+            ));
+            std::fs::copy(path, &backup).wrap_err("failed to back up index before migrating")?;
+
+            info!(
+                "migrating index from schema version {} to {}, the old index was backed up to {}",
+                version,
+                migrations::SCHEMA_VERSION,
+                backup.display()
+            );
+
+            for migration in migrations::migrate(&mut index)? {
+                info!(
+                    "migrated index to schema version {}: {}",
+                    migration.version, migration.description
+                );
+            }
         }
+
+        Ok(serde_json::from_value(index)?)
     }
 
     pub fn is_refreshing() -> bool {
         IS_REFRESHING.load(Ordering::Relaxed)
     }
 
-    pub fn save(&self) -> Result<()> {
+    /// Asks the running refresh to stop, killing the yt-dlp processes that
+    /// are running. Returns `false` if a shutdown was already requested.
+    pub fn request_shutdown() -> bool {
+        !SHUTDOWN_REQUESTED.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn is_shutdown_requested() -> bool {
+        SHUTDOWN_REQUESTED.load(Ordering::Relaxed)
+    }
+
+    /// Saves the index, backing up the previous version first.
+    pub fn save(&mut self) -> Result<()> {
+        backup_index().wrap_err("failed to back up index")?;
+
+        self.checkpoint()
+    }
+
+    /// Saves the index without backing up the previous version, which is used
+    /// to save progress during refreshes. Either way the index is stored, a
+    /// crash while saving leaves the previous version in place.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        let transitions = std::mem::take(&mut self.transitions);
+        let changes = std::mem::take(&mut self.changes);
+        let config = AppConfig::get();
+
+        let result = match config.index_storage {
+            // the journal is appended to before the index is saved, so a
+            // transition the index knows about is always journaled. if saving
+            // the index fails, the transitions are journaled again by the next
+            // checkpoint, and the duplicates are skipped when the journal is
+            // read
+            IndexStorage::Json => journal::append(&config.paths.journal, &transitions)
+                .and_then(|()| self.write_json()),
+            // the transitions are the journal, and they are written in the
+            // same transaction as the index
+            IndexStorage::Sqlite => database::save(self, &changes, &transitions),
+        };
+
+        if let Err(err) = result {
+            // the transitions and changes are saved with the next checkpoint
+            // instead
+            self.transitions.splice(0..0, transitions);
+            self.changes.extend(changes);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    fn write_json(&self) -> Result<()> {
         let path = &AppConfig::get().paths.index;
 
         #[cfg(debug_assertions)]
@@ -168,18 +618,62 @@ impl AppIndex {
         #[cfg(not(debug_assertions))]
         let to_string = serde_json::to_string;
 
+        let mut index = serde_json::to_value(self)?;
+        index[migrations::SCHEMA_VERSION_KEY] = migrations::SCHEMA_VERSION.into();
+
+        let contents = to_string(&index)?;
+
         trace!("saving index to {}", path.display());
-        std::fs::write(path, to_string(self)?)?;
+        util::write_atomic(path, contents.as_bytes()).wrap_err("failed to write index")?;
 
         Ok(())
     }
 
+    /// Refreshes every source, saving the index after every source and
+    /// regularly in between. The index is backed up once before the refresh.
     #[instrument(skip(self))]
     pub fn refresh(&mut self) -> Result<()> {
+        backup_index().wrap_err("failed to back up index")?;
+
+        self.refresh_with(&AppConfig::get().sources, &SourceFetcher, Self::checkpoint)?;
+
+        self.checkpoint()?;
+
+        Ok(())
+    }
+
+    /// Refreshes the given sources using `fetcher`, updating the index and
+    /// writing the playlist definitions. `checkpoint` is called after every
+    /// source, and in between once `checkpoint_actions` actions were handled
+    /// or `checkpoint_interval` seconds passed (see [`AppConfig`]).
+    ///
+    /// If a shutdown is requested (see [`AppIndex::request_shutdown`]), the
+    /// downloads in progress are stopped and the refresh stops early, leaving
+    /// the rest of the work for the next refresh.
+    #[instrument(skip_all)]
+    pub fn refresh_with<F, C>(
+        &mut self,
+        sources: &[SourceDefinition],
+        fetcher: &F,
+        mut checkpoint: C,
+    ) -> Result<()>
+    where
+        F: Fetcher + Sync,
+        C: FnMut(&mut Self) -> Result<()>,
+    {
         trace!("refreshing index");
-        IS_REFRESHING.store(true, Ordering::Relaxed);
+        let _refreshing = RefreshGuard::start();
+        self.run_id = Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string();
+        info!("starting refresh {}", self.run_id);
+
+        let mut progress = Progress::new();
+
+        'sources: for source in sources {
+            if Self::is_shutdown_requested() {
+                info!("shutdown requested, stopping refresh");
+                break;
+            }
 
-        for source in &AppConfig::get().sources {
             info!("updating source: {}", source.url);
 
             // if the source is inactive and has been indexed before, don't
@@ -194,14 +688,80 @@ impl AppIndex {
                 continue;
             }
 
-            let manifest = source.kind.fetch_playlist(source)?;
+            let previous_manifest = self.resolve_playlist(&source.url);
 
-            let (new_tracks, missing_tracks) =
-                if let Some(previous_manifest) = self.playlists.get(&source.url) {
-                    util::diff_with(&previous_manifest.entries, &manifest.entries, cmp_ids)
-                } else {
-                    (manifest.entries.iter().collect(), Vec::new())
-                };
+            // which proxy the playlist was reachable through isn't recorded,
+            // since its tracks are tried through the proxies on their own
+            let result = with_proxy_fallback(
+                source,
+                &fetcher.proxies(source),
+                &source.url,
+                &mut HashMap::new(),
+                |source| fetcher.fetch_playlist(source, previous_manifest.as_ref()),
+                |result| {
+                    result.as_ref().is_err_and(|err| {
+                        FailureReason::classify_error(err) == Some(FailureReason::GeoBlocked)
+                    })
+                },
+            );
+
+            // yt-dlp is killed on shutdown, so the fetch may have failed
+            // because of it
+            if Self::is_shutdown_requested() {
+                info!("shutdown requested, stopping refresh");
+                break;
+            }
+
+            let mut manifest = result?;
+            fill_download_info(&mut manifest.entries, Some(&self.tracks), source);
+
+            // maps the ID of every track whose audio was replaced to the
+            // version we had before, which is kept if the new version can't be
+            // downloaded
+            let mut replaced = HashMap::new();
+
+            if let Some(previous_manifest) = &previous_manifest {
+                let detection = &AppConfig::get().replacement_detection;
+
+                for track in &mut manifest.entries {
+                    // new tracks are compared with the version we have once
+                    // they are part of the source. the track may have been
+                    // replaced in another source since this one was refreshed
+                    let Some(previous) = self
+                        .tracks
+                        .get(&track.id)
+                        .filter(|_| previous_manifest.entries.iter().any(|t| t.id == track.id))
+                        .map(|record| record.entry(track.idx))
+                    else {
+                        continue;
+                    };
+
+                    if previous.file_ext.is_none() || !detection.is_replaced(&previous, track) {
+                        continue;
+                    }
+
+                    info!(
+                        "track {:?} ({}) was replaced by the uploader",
+                        track.title, track.id
+                    );
+
+                    // the new version is downloaded next to the old one, since
+                    // we never delete audio
+                    track.version = previous.version + 1;
+                    track.file_ext = None;
+                    track.master_ext = None;
+                    track.video_ext = None;
+                    track.sponsor_segments = None;
+
+                    replaced.insert(track.id.clone(), previous);
+                }
+            }
+
+            let (new_tracks, missing_tracks) = if let Some(previous_manifest) = &previous_manifest {
+                util::diff_with(&previous_manifest.entries, &manifest.entries, cmp_ids)
+            } else {
+                (manifest.entries.iter().collect(), Vec::new())
+            };
 
             info!(
                 "{} new tracks, {} tracks unaccounted for",
@@ -215,120 +775,348 @@ impl AppIndex {
             let mut removed_tracks = Vec::new();
             // tracks that became geo restricted
             let mut restricted_tracks = Vec::new();
+            // tracks whose status we couldn't determine, which keep their
+            // previous state until the next refresh
+            let mut skipped_tracks = Vec::new();
 
             for track in missing_tracks {
-                match source.kind.fetch_track(track)? {
+                // nothing was recorded for the source yet, so it is checked
+                // again from the start on the next refresh
+                if Self::is_shutdown_requested() {
+                    info!("shutdown requested, stopping refresh");
+                    break 'sources;
+                }
+
+                let result = with_proxy_fallback(
+                    source,
+                    &fetcher.proxies(source),
+                    &track.id,
+                    &mut self.proxied,
+                    |source| fetcher.fetch_track(source, track),
+                    |result| {
+                        matches!(
+                            result,
+                            Ok(TrackStatus::Unavailable(FailureReason::GeoBlocked))
+                        )
+                    },
+                );
+
+                let status = match result {
+                    Ok(status) => status,
+                    Err(err) => {
+                        warn!(
+                            "could not determine the status of {:?} ({}), skipping it: {:?}",
+                            track.title, track.id, err
+                        );
+                        skipped_tracks.push(track.clone());
+                        continue;
+                    }
+                };
+
+                let reason = match status {
+                    // geo-restricted tracks are part of the manifest as long
+                    // as the source has proxies they are reachable through
+                    // (see `Fetcher::fetch_playlist`), so if the track is
+                    // still available (through its proxy or without one), it
+                    // was manually removed from the playlist
                     TrackStatus::Available(_) => {
-                        // if the track is still available, it was manually
-                        // removed from the playlist
                         removed_tracks.push(track);
+                        continue;
                     }
-                    TrackStatus::Restricted => {
-                        // if the track is geo restricted, it was not manually
-                        // removed from the playlist, but it is no longer
-                        // available
-                        restricted_tracks.push(track);
+                    TrackStatus::Unavailable(reason) => reason,
+                };
+
+                match reason.kind() {
+                    // if the track is restricted, it was not manually removed
+                    // from the playlist, but it is no longer available
+                    FailureKind::Restricted => restricted_tracks.push((track, reason)),
+                    // if the track is not found, it was deleted from the
+                    // platform
+                    FailureKind::Deleted => deleted_tracks.push((track, reason)),
+                    // expired cookies affect every track of the source, so
+                    // the source is left as it was until they are replaced.
+                    // the other sources may not use them
+                    FailureKind::Transient if reason == FailureReason::CookiesExpired => {
+                        error!(
+                            "could not determine the status of {:?} ({}): {}, skipping source {}",
+                            track.title, track.id, reason, source.url
+                        );
+                        continue 'sources;
                     }
-                    TrackStatus::NotFound => {
-                        // if the track is not found, it was deleted from
-                        // SoundCloud
-                        deleted_tracks.push(track);
+                    // we can't tell what happened to the track, so instead of
+                    // guessing we leave it as it was until the next refresh
+                    FailureKind::Transient => {
+                        warn!(
+                            "could not determine the status of {:?} ({}), skipping it: {}",
+                            track.title, track.id, reason
+                        );
+                        skipped_tracks.push(track.clone());
                     }
                 }
             }
 
             info!(
-                "{} deleted tracks, {} removed tracks, {} restricted tracks",
+                "{} deleted tracks, {} removed tracks, {} restricted tracks, {} skipped tracks",
                 deleted_tracks.len(),
                 removed_tracks.len(),
-                restricted_tracks.len()
+                restricted_tracks.len(),
+                skipped_tracks.len()
             );
 
-            let (deleted_tracks, undeleted_tracks) =
-                if let Some(previously_deleted) = self.deleted.get(&source.url) {
-                    util::diff_ref_with(previously_deleted, deleted_tracks, cmp_ids)
-                } else {
-                    (deleted_tracks, Vec::new())
-                };
-
-            debug!(
-                "{} deleted tracks, {} undeleted tracks",
-                deleted_tracks.len(),
-                undeleted_tracks.len()
+            // tracks that were deleted, removed or restricted in an earlier
+            // refresh are not part of the previous manifest, so they can only
+            // come back by reappearing in the new manifest. they are handled
+            // using their new manifest
+            let (undeleted_tracks, previously_deleted) = partition_returned(
+                self.deleted.get(&source.url).cloned().unwrap_or_default(),
+                &new_tracks,
+                |u| &u.track.id,
             );
-
-            let (removed_tracks, unremoved_tracks) =
-                if let Some(previously_removed) = self.removed.get(&source.url) {
-                    util::diff_ref_with(previously_removed, removed_tracks, |t1, t2| t1.id == t2.id)
-                } else {
-                    (removed_tracks, Vec::new())
-                };
-
-            debug!(
-                "{} removed tracks, {} unremoved tracks",
-                removed_tracks.len(),
-                unremoved_tracks.len()
+            let (unremoved_tracks, previously_removed) = partition_returned(
+                self.removed.get(&source.url).cloned().unwrap_or_default(),
+                &new_tracks,
+                |t| &t.id,
+            );
+            let (unrestricted_tracks, previously_restricted) = partition_returned(
+                self.restricted
+                    .get(&source.url)
+                    .cloned()
+                    .unwrap_or_default(),
+                &new_tracks,
+                |u| &u.track.id,
             );
-
-            let (restricted_tracks, unrestricted_tracks) =
-                if let Some(previously_restricted) = self.restricted.get(&source.url) {
-                    util::diff_ref_with(previously_restricted, restricted_tracks, cmp_ids)
-                } else {
-                    (restricted_tracks, Vec::new())
-                };
 
             debug!(
-                "{} restricted tracks, {} unrestricted tracks",
-                restricted_tracks.len(),
+                "{} undeleted tracks, {} unremoved tracks, {} unrestricted tracks",
+                undeleted_tracks.len(),
+                unremoved_tracks.len(),
                 unrestricted_tracks.len()
             );
 
+            let returned = undeleted_tracks
+                .iter()
+                .chain(&unremoved_tracks)
+                .chain(&unrestricted_tracks)
+                .map(|t| &t.id)
+                .collect::<HashSet<_>>();
+
             let mut actions = Vec::new();
 
-            actions.extend(deleted_tracks.iter().map(|t| act!(t = Deleted)));
-            actions.extend(undeleted_tracks.iter().map(|t| act!(t = Undeleted)));
-            actions.extend(removed_tracks.iter().map(|t| act!(t = Removed)));
-            actions.extend(unremoved_tracks.iter().map(|t| act!(t = Unremoved)));
-            actions.extend(restricted_tracks.iter().map(|t| act!(t = Restricted)));
-            actions.extend(unrestricted_tracks.iter().map(|t| act!(t = Unrestricted)));
+            actions.extend(
+                deleted_tracks
+                    .iter()
+                    .map(|&(t, reason)| act!(t = Deleted(reason))),
+            );
+            actions.extend(undeleted_tracks.iter().map(|&t| act!(t = Undeleted)));
+            actions.extend(removed_tracks.iter().map(|&t| act!(t = Removed)));
+            actions.extend(unremoved_tracks.iter().map(|&t| act!(t = Unremoved)));
+            actions.extend(
+                restricted_tracks
+                    .iter()
+                    .map(|&(t, reason)| act!(t = Restricted(reason))),
+            );
+            actions.extend(unrestricted_tracks.iter().map(|&t| act!(t = Unrestricted)));
 
             // we want the add operations to come last so the downloads are done
             // last. this is done so if there are errors in the code handling
             // track state changes, we will know before we download tons of
             // audio and crash which would throw away all of the progress we
             // made
-            actions.extend(new_tracks.iter().map(|t| act!(t = Added)));
+            actions.extend(
+                new_tracks
+                    .iter()
+                    .filter(|t| !returned.contains(&t.id))
+                    .map(|t| act!(t = Added)),
+            );
+            actions.extend(
+                manifest
+                    .entries
+                    .iter()
+                    .filter(|t| replaced.contains_key(&t.id))
+                    .map(|t| act!(t = Replaced)),
+            );
 
             info!("{} actions to handle", actions.len());
 
+            // downloads are collected and performed after every other
+            // operation, since they are the only operations that can be done
+            // in parallel
+            let mut downloads: Vec<(Track, Action, Vec<Operation>)> = Vec::new();
+
             for action in actions {
+                if Self::is_shutdown_requested() {
+                    // the actions that were handled so far are already
+                    // recorded, so the rest of them are handled on the next
+                    // refresh
+                    info!("shutdown requested, stopping refresh");
+                    break 'sources;
+                }
+
                 debug!(
                     "handling action {:?} on track {}",
                     action.action, action.track.title
                 );
                 let track = action.track;
 
-                let operations = action.action.necessary_operations();
+                let mut operations = action.action.necessary_operations().into_iter();
+                let mut downloading = false;
+
+                while let Some(op) = operations.next() {
+                    if let Operation::Download = op {
+                        // the download and the operations that depend on it
+                        // are performed in parallel
+                        downloads.push((
+                            track.clone(),
+                            action.action,
+                            iter::once(op).chain(operations).collect(),
+                        ));
+                        downloading = true;
+                        break;
+                    }
 
-                for op in operations {
                     trace!("performing operation {:?}", op);
-                    op.perform(source.kind, track, &manifest)?;
+                    op.perform(fetcher, source, track, &manifest, &mut self.proxied)?;
+                }
+
+                // downloads are recorded once they are done
+                if !downloading {
+                    self.record_action(source, &manifest, track, action.action);
+
+                    if progress.record() {
+                        checkpoint(self)?;
+                    }
                 }
             }
 
+            let workers = source.download_workers();
+            info!(
+                "downloading {} tracks with {} workers",
+                downloads.len(),
+                workers
+            );
+
+            // each download only touches the proxy record of its own track
+            let known_proxies = self.proxied.clone();
+            let mut failed = HashSet::new();
+            let mut checkpoint_result = Ok(());
+
+            util::parallel_for_each(
+                &downloads,
+                workers,
+                |(track, _, operations)| {
+                    // downloads that haven't started are left for the next
+                    // refresh
+                    if Self::is_shutdown_requested() {
+                        return None;
+                    }
+
+                    let mut proxied = known_proxies
+                        .get_key_value(&track.id)
+                        .map(|(id, name)| HashMap::from([(id.clone(), name.clone())]))
+                        .unwrap_or_default();
+
+                    let result = operations.iter().try_for_each(|op| {
+                        trace!("performing operation {:?}", op);
+                        op.clone()
+                            .perform(fetcher, source, track, &manifest, &mut proxied)
+                    });
+
+                    Some((result, proxied))
+                },
+                |idx, outcome| {
+                    let (track, action, _) = &downloads[idx];
+
+                    match outcome {
+                        Some((Ok(()), proxied)) => {
+                            self.proxied.remove(&track.id);
+                            self.proxied.extend(proxied);
+
+                            // the files of the track are only known now
+                            let mut track = track.clone();
+                            fill_download_info(std::slice::from_mut(&mut track), None, source);
+                            self.record_action(source, &manifest, &track, *action);
+
+                            if progress.record() && checkpoint_result.is_ok() {
+                                checkpoint_result = checkpoint(self);
+                            }
+                        }
+                        Some((Err(err), _)) => {
+                            // the track is left out of the index, so the
+                            // download is retried on the next refresh
+                            if Self::is_shutdown_requested() {
+                                debug!(
+                                    "download of {} stopped to shut down: {:?}",
+                                    track.title, err
+                                );
+                            } else {
+                                error!("failed to download track {}: {:?}", track.title, err);
+                            }
+                            failed.insert(&track.id);
+                        }
+                        None => {
+                            debug!("skipping download of {} to shut down", track.title);
+                            failed.insert(&track.id);
+                        }
+                    }
+                },
+            );
+
+            checkpoint_result?;
+
+            let unavailable = |(track, reason): (&Track, FailureReason)| UnavailableTrack {
+                track: TrackRef::from(track),
+                reason: Some(reason),
+            };
+
             self.deleted.insert(
                 source.url.clone(),
-                deleted_tracks.into_iter().cloned().collect(),
+                previously_deleted
+                    .into_iter()
+                    .chain(deleted_tracks.into_iter().map(unavailable))
+                    .collect(),
             );
             self.removed.insert(
                 source.url.clone(),
-                removed_tracks.into_iter().cloned().collect(),
+                previously_removed
+                    .into_iter()
+                    .chain(removed_tracks.into_iter().map(TrackRef::from))
+                    .collect(),
             );
             self.restricted.insert(
                 source.url.clone(),
-                restricted_tracks.into_iter().cloned().collect(),
+                previously_restricted
+                    .into_iter()
+                    .chain(restricted_tracks.into_iter().map(unavailable))
+                    .collect(),
             );
-            self.playlists.insert(source.url.clone(), manifest);
+            manifest.entries.extend(skipped_tracks);
+            for track in &mut manifest.entries {
+                if failed.contains(&track.id)
+                    && let Some(previous) = replaced.get(&track.id)
+                {
+                    // we keep pointing at the version we already have
+                    *track = Track {
+                        idx: track.idx,
+                        ..previous.clone()
+                    };
+                }
+            }
+            manifest
+                .entries
+                .retain(|t| !failed.contains(&t.id) || replaced.contains_key(&t.id));
+            // the files of the tracks we just downloaded are only known now
+            fill_download_info(&mut manifest.entries, None, source);
+
+            self.sync_registry(
+                &source.url,
+                manifest,
+                Some(source.kind.platform()),
+                Some(Utc::now()),
+            );
+            self.changes.sources.insert(source.url.clone());
+
+            checkpoint(self)?;
+            progress = Progress::new();
         }
 
         // currently we write the playlists per-track as an Operation, but this
@@ -342,13 +1130,971 @@ impl AppIndex {
             // is a bit failure prone, we will retry writing the playlist a few
             // times before giving up. the default retry policy is 3 retries
             // with an exponential backoff
-            retry_with(|| write_playlist(playlist), "failed to write playlist")?;
+            retry_with(
+                || write_playlist(playlist, &self.tracks),
+                "failed to write playlist",
+            )?;
         }
 
-        IS_REFRESHING.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Records an action that was handled in the index, so it isn't handled
+    /// again if the refresh is interrupted. The state of the source is replaced
+    /// with its complete new state once every action was handled.
+    fn record_action(
+        &mut self,
+        source: &SourceDefinition,
+        manifest: &Playlist,
+        track: &Track,
+        action: Action,
+    ) {
+        let url = source.url.as_str();
+        let now = Utc::now();
 
-        self.save()?;
+        let record = self
+            .tracks
+            .entry(track.id.clone())
+            .or_insert_with(|| TrackRecord::new(track));
 
-        Ok(())
+        match action {
+            Action::Removed => record.left(url, None),
+            Action::Deleted(reason) => record.left(url, Some(Availability::Deleted(Some(reason)))),
+            Action::Restricted(reason) => {
+                record.left(url, Some(Availability::Restricted(Some(reason))))
+            }
+            _ => record.seen_in(track, url, Some(source.kind.platform()), Some(now)),
+        }
+
+        if let Action::Added | Action::Replaced = action {
+            record.read_file_info();
+        }
+
+        self.changes.tracks.insert(track.id.clone());
+        self.changes.playlists.insert(url.to_string());
+        self.changes
+            .memberships
+            .insert((url.to_string(), track.id.clone()));
+
+        let playlist = self
+            .playlists
+            .entry(url.to_string())
+            .or_insert_with(|| IndexedPlaylist {
+                id: manifest.id.clone(),
+                title: manifest.title.clone(),
+                entries: Vec::new(),
+                url: manifest.url.clone(),
+                len: manifest.len,
+                sections: Vec::new(),
+            });
+
+        playlist.entries.retain(|t| t.id != track.id);
+
+        self.transitions.push(Transition {
+            run_id: self.run_id.clone(),
+            source_url: url.to_string(),
+            track_id: track.id.clone(),
+            uploader: track.uploader.clone(),
+            title: track.title.clone(),
+            action,
+            time: now,
+        });
+
+        let unavailable = |reason| UnavailableTrack {
+            track: TrackRef::from(track),
+            reason: Some(reason),
+        };
+
+        match action {
+            Action::Added | Action::Replaced => playlist.entries.push(TrackRef::from(track)),
+            Action::Removed => self
+                .removed
+                .entry(url.to_string())
+                .or_default()
+                .push(TrackRef::from(track)),
+            Action::Unremoved => {
+                playlist.entries.push(TrackRef::from(track));
+                if let Some(removed) = self.removed.get_mut(url) {
+                    removed.retain(|t| t.id != track.id);
+                }
+            }
+            Action::Deleted(reason) => self
+                .deleted
+                .entry(url.to_string())
+                .or_default()
+                .push(unavailable(reason)),
+            Action::Undeleted => {
+                playlist.entries.push(TrackRef::from(track));
+                if let Some(deleted) = self.deleted.get_mut(url) {
+                    deleted.retain(|u| u.track.id != track.id);
+                }
+            }
+            Action::Restricted(reason) => self
+                .restricted
+                .entry(url.to_string())
+                .or_default()
+                .push(unavailable(reason)),
+            Action::Unrestricted => {
+                playlist.entries.push(TrackRef::from(track));
+                if let Some(restricted) = self.restricted.get_mut(url) {
+                    restricted.retain(|u| u.track.id != track.id);
+                }
+            }
+        }
+    }
+
+    /// Returns the playlist of a source as it was last refreshed, with the
+    /// latest manifest of every track from the registry.
+    pub fn resolve_playlist(&self, url: &str) -> Option<Playlist> {
+        let playlist = self.playlists.get(url)?;
+
+        Some(Playlist {
+            id: playlist.id.clone(),
+            title: playlist.title.clone(),
+            entries: playlist
+                .entries
+                .iter()
+                .filter_map(|t| t.resolve(&self.tracks))
+                .collect(),
+            url: playlist.url.clone(),
+            len: playlist.len,
+            sections: playlist.sections.clone(),
+        })
+    }
+
+    /// Stores the playlist of a source and updates the registry with the
+    /// state of the source: the tracks that are part of it are recorded as
+    /// seen (with their manifest from `playlist`), and the tracks that were
+    /// removed from it or became unavailable as no longer part of it. `now` is
+    /// `None` if it isn't known when the source was refreshed.
+    pub fn sync_registry(
+        &mut self,
+        url: &str,
+        playlist: Playlist,
+        platform: Option<Platform>,
+        now: Option<DateTime<Utc>>,
+    ) {
+        for track in &playlist.entries {
+            let record = self
+                .tracks
+                .entry(track.id.clone())
+                .or_insert_with(|| TrackRecord::new(track));
+
+            record.seen_in(track, url, platform, now);
+
+            // tracks downloaded before the registry existed
+            if record.downloaded_at.is_none() {
+                record.read_file_info();
+            }
+
+            self.changes.tracks.insert(track.id.clone());
+        }
+
+        self.playlists
+            .insert(url.to_string(), IndexedPlaylist::from(playlist));
+
+        let removed = self
+            .removed
+            .get(url)
+            .into_iter()
+            .flatten()
+            .map(|t| (t, None));
+        let deleted = self
+            .deleted
+            .get(url)
+            .into_iter()
+            .flatten()
+            .map(|u| (&u.track, Some(Availability::Deleted(u.reason))));
+        let restricted = self
+            .restricted
+            .get(url)
+            .into_iter()
+            .flatten()
+            .map(|u| (&u.track, Some(Availability::Restricted(u.reason))));
+
+        for (track, availability) in removed.chain(deleted).chain(restricted) {
+            // the tracks were recorded when they left the source
+            if let Some(record) = self.tracks.get_mut(&track.id) {
+                record.left(url, availability);
+                self.changes.tracks.insert(track.id.clone());
+            }
+        }
+    }
+}
+
+/// Fills in the information about the downloaded files (the extensions of the
+/// playback file, master and video, and the applied SponsorBlock segments) of
+/// tracks that don't have it (e.g. because they come from a freshly fetched
+/// manifest) using the registry, or the files on disk.
+fn fill_download_info(
+    entries: &mut [Track],
+    registry: Option<&HashMap<String, TrackRecord>>,
+    source: &SourceDefinition,
+) {
+    for track in entries.iter_mut().filter(|t| t.file_ext.is_none()) {
+        let known = registry
+            .and_then(|registry| registry.get(&track.id))
+            .map(|record| &record.track)
+            .filter(|known| known.file_ext.is_some());
+
+        if let Some(known) = known {
+            track.file_ext.clone_from(&known.file_ext);
+            track.master_ext.clone_from(&known.master_ext);
+            track.video_ext.clone_from(&known.video_ext);
+            track.sponsor_segments.clone_from(&known.sponsor_segments);
+            track.version = known.version;
+        } else {
+            track.version = track.find_latest_version();
+            let handle = track.as_handle();
+            track.file_ext = handle.find_file_ext();
+            track.master_ext = handle.find_master_ext();
+            track.video_ext = handle.find_video_ext();
+            track.sponsor_segments = source
+                .sponsorblock
+                .as_ref()
+                .and_then(|options| sponsorblock::read_saved(&handle, options));
+        }
+    }
+}
+
+/// Copies the current index into the backups folder, then deletes the oldest
+/// backups so only `AppConfig.index_backups` are kept.
+fn backup_index() -> Result<()> {
+    let config = AppConfig::get();
+
+    let ext = match config.index_storage {
+        IndexStorage::Json => "json",
+        IndexStorage::Sqlite => "sqlite",
+    };
+
+    if config.index_backups == 0 {
+        return Ok(());
+    }
+
+    let backup = config
+        .paths
+        .backups
+        .join(format!("index-{}.{ext}", backup_timestamp()));
+
+    match config.index_storage {
+        IndexStorage::Json if config.paths.index.exists() => {
+            trace!("backing up index to {}", backup.display());
+            std::fs::copy(&config.paths.index, &backup)?;
+        }
+        IndexStorage::Sqlite => {
+            trace!("backing up database to {}", backup.display());
+            database::backup(&backup)?;
+        }
+        // there is nothing to back up yet
+        IndexStorage::Json => return Ok(()),
+    }
+
+    let backups = list_backups(ext)?;
+    let excess = backups.len().saturating_sub(config.index_backups);
+
+    for old in &backups[..excess] {
+        trace!("deleting old backup {}", old.display());
+        std::fs::remove_file(old)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the paths of the index backups with the given extension, from
+/// oldest to newest.
+fn list_backups(ext: &str) -> Result<Vec<PathBuf>> {
+    let dir = &AppConfig::get().paths.backups;
+
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    backups.retain(|path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("index-"))
+            .is_some_and(|name| name.ends_with(&format!(".{ext}")))
+    });
+    // the timestamps sort chronologically
+    backups.sort();
+
+    Ok(backups)
+}
+
+/// A timestamp for file names that sorts chronologically.
+fn backup_timestamp() -> String {
+    Utc::now().format("%Y%m%dT%H%M%S%.6fZ").to_string()
+}
+
+/// Splits tracks that were unavailable after the last refresh into the ones
+/// that are back in the playlist (as their entries in `new_tracks`) and the
+/// ones that are still missing from it.
+fn partition_returned<'a, T>(
+    previous: Vec<T>,
+    new_tracks: &[&'a Track],
+    id: impl Fn(&T) -> &String,
+) -> (Vec<&'a Track>, Vec<T>) {
+    let mut returned = Vec::new();
+    let mut gone = Vec::new();
+
+    for item in previous {
+        match new_tracks.iter().find(|t| &t.id == id(&item)) {
+            Some(track) => returned.push(*track),
+            None => gone.push(item),
+        }
+    }
+
+    (returned, gone)
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::bail;
+
+    use super::*;
+    use crate::source::scripted::ScriptedFetcher;
+
+    fn source(url: &str) -> SourceDefinition {
+        AppConfig::initialize();
+        AppConfig::get().paths.ensure_all().unwrap();
+
+        serde_json::from_str(&format!(r#"{{ "type": "generic", "url": "{url}" }}"#)).unwrap()
+    }
+
+    fn track(id: &str) -> Track {
+        Track {
+            id: id.to_string(),
+            uploader: "uploader".to_string(),
+            title: format!("track {id}"),
+            url: format!("https://example.com/{id}"),
+            idx: 0,
+            file_ext: None,
+            master_ext: None,
+            video_ext: None,
+            sponsor_segments: None,
+            version: 1,
+            metadata: Default::default(),
+        }
+    }
+
+    /// Removes any files left behind by a previous run of a test.
+    fn clean(tracks: &[&Track]) {
+        for track in tracks {
+            let _ = std::fs::remove_dir_all(track.as_handle().root_dir);
+        }
+    }
+
+    fn ids<'a>(tracks: impl IntoIterator<Item = &'a TrackRef>) -> Vec<&'a str> {
+        let mut ids = tracks
+            .into_iter()
+            .map(|t| t.id.as_str())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    /// Returns the metadata markers written to a track.
+    fn markers(track: &Track) -> Vec<String> {
+        id3::Tag::read_from_path(track.as_handle().track_path)
+            .unwrap()
+            .comments()
+            .map(|c| c.text.trim_end().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_markers_non_mp3() {
+        let src = source("https://example.com/markers");
+
+        let mut a = track("mk-a");
+        a.file_ext = Some("opus".to_string());
+        clean(&[&a]);
+
+        let handle = a.as_handle();
+        std::fs::create_dir_all(&handle.root_dir).unwrap();
+        std::fs::write(&handle.track_path, []).unwrap();
+
+        let playlist = Playlist {
+            id: "markers".to_string(),
+            title: "markers".to_string(),
+            entries: Vec::new(),
+            url: src.url.clone(),
+            len: 0,
+            sections: Vec::new(),
+        };
+
+        for action in [Action::Removed, Action::Unremoved] {
+            Operation::AddMetadataMarker(action)
+                .perform(
+                    &ScriptedFetcher::default(),
+                    &src,
+                    &a,
+                    &playlist,
+                    &mut HashMap::new(),
+                )
+                .unwrap();
+        }
+
+        // markers can't be embedded in opus files, so they are only saved
+        // next to the track
+        assert_eq!(
+            std::fs::read_to_string(handle.root_dir.join("markers.txt")).unwrap(),
+            "This track was removed from the playlist. markers (markers)\n\
+             This track was added back to the playlist. markers (markers)\n"
+        );
+    }
+
+    #[test]
+    fn test_refresh_transitions() {
+        let src = source("https://example.com/transitions");
+        let url = src.url.as_str();
+        let sources = [src.clone()];
+
+        let [a, b, c, d, e] = ["tr-a", "tr-b", "tr-c", "tr-d", "tr-e"].map(track);
+        clean(&[&a, &b, &c, &d, &e]);
+
+        let fetcher = ScriptedFetcher::default();
+        let mut index = AppIndex::default();
+
+        // first refresh: everything is new
+        fetcher.set_playlist(url, &[&a, &b, &c, &d]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(fetcher.take_downloads(), ["tr-a", "tr-b", "tr-c", "tr-d"]);
+        assert_eq!(
+            ids(&index.playlists[url].entries),
+            ["tr-a", "tr-b", "tr-c", "tr-d"]
+        );
+        assert!(
+            index.playlists[url]
+                .entries
+                .iter()
+                .all(|t| index.tracks[&t.id].track.file_ext.as_deref() == Some("mp3"))
+        );
+
+        // second refresh: b is removed from the playlist, c is deleted, d is
+        // made private and e is added
+        fetcher.set_playlist(url, &[&a, &e]);
+        fetcher.set_unavailable(&c.id, FailureReason::RemovedByUploader);
+        fetcher.set_unavailable(&d.id, FailureReason::Private);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(fetcher.take_downloads(), ["tr-e"]);
+        assert_eq!(ids(&index.playlists[url].entries), ["tr-a", "tr-e"]);
+        assert_eq!(ids(&index.removed[url]), ["tr-b"]);
+        assert_eq!(ids(index.deleted[url].iter().map(|u| &u.track)), ["tr-c"]);
+        assert_eq!(
+            index.deleted[url][0].reason,
+            Some(FailureReason::RemovedByUploader)
+        );
+        assert_eq!(
+            ids(index.restricted[url].iter().map(|u| &u.track)),
+            ["tr-d"]
+        );
+        assert_eq!(
+            index.restricted[url][0].reason,
+            Some(FailureReason::Private)
+        );
+
+        assert!(markers(&b)[0].starts_with("This track was removed from the playlist."));
+        assert_eq!(markers(&c).len(), 1);
+        assert_eq!(markers(&d).len(), 1);
+
+        // third refresh: nothing changed, so the tracks stay where they are
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert!(fetcher.take_downloads().is_empty());
+        assert_eq!(ids(&index.removed[url]), ["tr-b"]);
+        assert_eq!(index.deleted[url].len(), 1);
+        assert_eq!(index.restricted[url].len(), 1);
+        for t in [&b, &c, &d] {
+            assert_eq!(markers(t).len(), 1, "{}", t.id);
+        }
+
+        // fourth refresh: every track comes back
+        fetcher.set_available(&c.id);
+        fetcher.set_available(&d.id);
+        fetcher.set_playlist(url, &[&a, &b, &c, &d, &e]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert!(fetcher.take_downloads().is_empty());
+        assert_eq!(
+            ids(&index.playlists[url].entries),
+            ["tr-a", "tr-b", "tr-c", "tr-d", "tr-e"]
+        );
+        assert!(index.removed[url].is_empty());
+        assert!(index.deleted[url].is_empty());
+        assert!(index.restricted[url].is_empty());
+
+        assert!(markers(&b)[1].starts_with("This track was added back to the playlist."));
+        assert!(markers(&c)[1].starts_with("This track was added back."));
+        assert!(markers(&d)[1].starts_with("This track was no longer restricted."));
+        assert!(markers(&a).is_empty());
+    }
+
+    #[test]
+    fn test_refresh_transient_failure() {
+        let [src, other] = [
+            "https://example.com/transient",
+            "https://example.com/transient-other",
+        ]
+        .map(source);
+        let (url, other_url) = (src.url.as_str(), other.url.as_str());
+        let sources = [src.clone(), other.clone()];
+
+        let [a, b, c, d] = ["tf-a", "tf-b", "tf-c", "tf-d"].map(track);
+        clean(&[&a, &b, &c, &d]);
+
+        let fetcher = ScriptedFetcher::default();
+        let mut index = AppIndex::default();
+
+        fetcher.set_playlist(url, &[&a, &b]);
+        fetcher.set_playlist(other_url, &[]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        // we can't tell what happened to b, so it is left as it was while
+        // the rest of the refresh goes on
+        fetcher.set_playlist(url, &[&a, &c]);
+        fetcher.set_unavailable(&b.id, FailureReason::RateLimited);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(fetcher.take_downloads(), ["tf-a", "tf-b", "tf-c"]);
+        assert_eq!(ids(&index.playlists[url].entries), ["tf-a", "tf-b", "tf-c"]);
+        assert!(index.deleted[url].is_empty());
+        assert!(markers(&b).is_empty());
+
+        // once we can tell, the track is handled as usual
+        fetcher.set_unavailable(&b.id, FailureReason::NotFound);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(ids(&index.playlists[url].entries), ["tf-a", "tf-c"]);
+        assert_eq!(ids(index.deleted[url].iter().map(|u| &u.track)), ["tf-b"]);
+
+        // expired cookies affect every track of the source, so it is left
+        // as it was, but the other sources are still refreshed
+        fetcher.take_downloads();
+        fetcher.set_playlist(url, &[&a]);
+        fetcher.set_playlist(other_url, &[&d]);
+        fetcher.set_unavailable(&c.id, FailureReason::CookiesExpired);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(ids(&index.playlists[url].entries), ["tf-a", "tf-c"]);
+        assert_eq!(ids(&index.playlists[other_url].entries), ["tf-d"]);
+        assert_eq!(fetcher.take_downloads(), ["tf-d"]);
+    }
+
+    #[test]
+    fn test_refresh_failed_download() {
+        let src = source("https://example.com/failed-download");
+        let url = src.url.as_str();
+        let sources = [src.clone()];
+
+        let [a, b] = ["fd-a", "fd-b"].map(track);
+        clean(&[&a, &b]);
+
+        let fetcher = ScriptedFetcher::default();
+        let mut index = AppIndex::default();
+
+        fetcher.set_playlist(url, &[&a, &b]);
+        fetcher.set_broken(&b.id, true);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        // the failed track is left out of the index...
+        assert_eq!(fetcher.take_downloads(), ["fd-a"]);
+        assert_eq!(ids(&index.playlists[url].entries), ["fd-a"]);
+
+        // ...so it is downloaded on the next refresh
+        fetcher.set_broken(&b.id, false);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(fetcher.take_downloads(), ["fd-b"]);
+        assert_eq!(ids(&index.playlists[url].entries), ["fd-a", "fd-b"]);
+    }
+
+    #[test]
+    fn test_refresh_replaced() {
+        let src = source("https://example.com/replaced");
+        let url = src.url.as_str();
+        let sources = [src.clone()];
+
+        let [mut a, b] = ["rp-a", "rp-b"].map(track);
+        clean(&[&a, &b]);
+
+        let fetcher = ScriptedFetcher::default();
+        let mut index = AppIndex::default();
+
+        a.metadata.duration = Some(180.0);
+        fetcher.set_playlist(url, &[&a, &b]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+        assert_eq!(fetcher.take_downloads(), ["rp-a", "rp-b"]);
+
+        // a small difference is not a replacement
+        a.metadata.duration = Some(181.0);
+        fetcher.set_playlist(url, &[&a, &b]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+        assert!(fetcher.take_downloads().is_empty());
+
+        // the new version is downloaded next to the old one
+        a.metadata.duration = Some(240.0);
+        fetcher.set_playlist(url, &[&a, &b]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(fetcher.take_downloads(), ["rp-a"]);
+        let entry = index.playlists[url].entries[0]
+            .resolve(&index.tracks)
+            .unwrap();
+        assert_eq!(entry.version, 2);
+        assert_eq!(entry.file_ext.as_deref(), Some("mp3"));
+        assert!(entry.as_handle().track_path.ends_with("rp-a/v2/track.mp3"));
+        assert!(a.as_handle().track_path.exists());
+
+        // nothing changed since the last refresh
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+        assert!(fetcher.take_downloads().is_empty());
+        assert_eq!(
+            index.playlists[url].entries[0]
+                .resolve(&index.tracks)
+                .unwrap()
+                .version,
+            2
+        );
+    }
+
+    #[test]
+    fn test_refresh_replaced_flat() {
+        let src = source("https://example.com/replaced-flat");
+        let url = src.url.as_str();
+        let sources = [src.clone()];
+
+        let [mut a, b] = ["rpf-a", "rpf-b"].map(track);
+        clean(&[&a, &b]);
+
+        let fetcher = ScriptedFetcher::flat();
+        let mut index = AppIndex::default();
+
+        a.metadata.duration = Some(180.0);
+        fetcher.set_playlist(url, &[&a, &b]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+        assert_eq!(fetcher.take_downloads(), ["rpf-a", "rpf-b"]);
+
+        // the listing only differs in metadata that doesn't count as a
+        // replacement, so the previous metadata is kept
+        a.metadata.duration = Some(181.0);
+        a.metadata.description = Some("new description".to_string());
+        fetcher.set_playlist(url, &[&a, &b]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert!(fetcher.take_downloads().is_empty());
+        let entry = index.playlists[url].entries[0]
+            .resolve(&index.tracks)
+            .unwrap();
+        assert_eq!(entry.metadata.duration, Some(180.0));
+        assert_eq!(entry.metadata.description, None);
+
+        // the listed duration changed, so the full manifest is fetched and the
+        // new version is downloaded
+        a.metadata.duration = Some(240.0);
+        fetcher.set_playlist(url, &[&a, &b]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(fetcher.take_downloads(), ["rpf-a"]);
+        let entry = index.playlists[url].entries[0]
+            .resolve(&index.tracks)
+            .unwrap();
+        assert_eq!(entry.version, 2);
+        assert_eq!(entry.metadata.duration, Some(240.0));
+        assert_eq!(
+            entry.metadata.description.as_deref(),
+            Some("new description")
+        );
+
+        // nothing changed since the last refresh
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+        assert!(fetcher.take_downloads().is_empty());
+        assert_eq!(
+            index.playlists[url].entries[0]
+                .resolve(&index.tracks)
+                .unwrap()
+                .version,
+            2
+        );
+    }
+
+    #[test]
+    fn test_refresh_geo_blocked_playlist() {
+        let src = source("https://example.com/geo-blocked");
+        let url = src.url.as_str();
+        let sources = [src.clone()];
+
+        let [a] = ["gb-a"].map(track);
+        clean(&[&a]);
+
+        let fetcher = ScriptedFetcher::default().with_proxy("test");
+        let mut index = AppIndex::default();
+
+        // the playlist is fetched through the proxy, and its tracks are
+        // downloaded without one since they aren't geo-restricted themselves
+        fetcher.set_playlist(url, &[&a]);
+        fetcher.set_geo_blocked(url, true);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(fetcher.take_downloads(), ["gb-a"]);
+        assert_eq!(ids(&index.playlists[url].entries), ["gb-a"]);
+        assert!(index.proxied.is_empty());
+    }
+
+    #[test]
+    fn test_refresh_geo_blocked_tracks() {
+        let src = source("https://example.com/geo-blocked-tracks");
+        let url = src.url.as_str();
+        let sources = [src.clone()];
+
+        let [a, b] = ["gt-a", "gt-b"].map(track);
+        clean(&[&a, &b]);
+
+        let fetcher = ScriptedFetcher::default().with_proxy("test");
+        let mut index = AppIndex::default();
+
+        // b is left out of the full manifest, but is part of the listing, so
+        // it is fetched and downloaded through the proxy
+        fetcher.set_playlist(url, &[&a, &b]);
+        fetcher.set_unavailable(&b.id, FailureReason::GeoBlocked);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(fetcher.take_downloads(), ["gt-a", "gt-b"]);
+        assert_eq!(ids(&index.playlists[url].entries), ["gt-a", "gt-b"]);
+        assert_eq!(index.proxied[&b.id], "test");
+
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+        assert_eq!(ids(&index.playlists[url].entries), ["gt-a", "gt-b"]);
+
+        // once b is removed from the playlist, it is still reachable through
+        // its proxy, so it was removed by the user
+        fetcher.set_playlist(url, &[&a]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(ids(&index.playlists[url].entries), ["gt-a"]);
+        assert_eq!(ids(&index.removed[url]), ["gt-b"]);
+    }
+
+    #[test]
+    fn test_refresh_replaced_failed_download() {
+        let src = source("https://example.com/replaced-failed");
+        let url = src.url.as_str();
+        let sources = [src.clone()];
+
+        let [mut a] = ["rf-a"].map(track);
+        clean(&[&a]);
+
+        let fetcher = ScriptedFetcher::default();
+        let mut index = AppIndex::default();
+
+        a.metadata.duration = Some(180.0);
+        fetcher.set_playlist(url, &[&a]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        // the old version is kept in the playlist until the new one is
+        // downloaded
+        a.metadata.duration = Some(240.0);
+        fetcher.set_playlist(url, &[&a]);
+        fetcher.set_broken(&a.id, true);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        let entry = index.playlists[url].entries[0]
+            .resolve(&index.tracks)
+            .unwrap();
+        assert_eq!(entry.version, 1);
+        assert_eq!(entry.metadata.duration, Some(180.0));
+
+        fetcher.set_broken(&a.id, false);
+        fetcher.take_downloads();
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(fetcher.take_downloads(), ["rf-a"]);
+        assert_eq!(
+            index.playlists[url].entries[0]
+                .resolve(&index.tracks)
+                .unwrap()
+                .version,
+            2
+        );
+    }
+
+    #[test]
+    fn test_save_and_recover() {
+        AppConfig::initialize();
+        let paths = &AppConfig::get().paths;
+        let _ = std::fs::remove_file(&paths.index);
+        let _ = std::fs::remove_dir_all(&paths.backups);
+        paths.ensure_all().unwrap();
+
+        let mut index = AppIndex::default();
+
+        for i in 0..8 {
+            index
+                .proxied
+                .insert("track".to_string(), format!("proxy {i}"));
+            index.save().unwrap();
+        }
+
+        // the first save had nothing to back up, and only the newest backups
+        // are kept
+        assert_eq!(list_backups("json").unwrap().len(), 5);
+        assert_eq!(AppIndex::load().unwrap().proxied["track"], "proxy 7");
+
+        std::fs::write(&paths.index, "{ \"playlists\": ").unwrap();
+
+        // the newest backup is the version before the last save
+        assert_eq!(AppIndex::load().unwrap().proxied["track"], "proxy 6");
+        assert!(!paths.index.exists());
+    }
+
+    #[test]
+    fn test_refresh_interrupted() {
+        let src = source("https://example.com/interrupted");
+        let url = src.url.as_str();
+        let sources = [src.clone()];
+
+        let [a, b, c, d] = ["in-a", "in-b", "in-c", "in-d"].map(track);
+        clean(&[&a, &b, &c, &d]);
+
+        let fetcher = ScriptedFetcher::default();
+        let mut index = AppIndex::default();
+
+        let mut checkpoints = 0;
+        fetcher.set_playlist(url, &[&a, &b, &c]);
+        index
+            .refresh_with(&sources, &fetcher, |_| {
+                checkpoints += 1;
+                Ok(())
+            })
+            .unwrap();
+
+        // after two of the three downloads (see `checkpoint_actions`), and
+        // after the source
+        assert_eq!(checkpoints, 2);
+        fetcher.take_downloads();
+
+        // the refresh stops once the first two actions were handled (c is
+        // deleted and b is removed)
+        fetcher.set_playlist(url, &[&a, &d]);
+        fetcher.set_unavailable(&c.id, FailureReason::NotFound);
+        assert!(
+            index
+                .refresh_with(&sources, &fetcher, |_| bail!("interrupted"))
+                .is_err()
+        );
+
+        assert_eq!(ids(&index.playlists[url].entries), ["in-a"]);
+        assert_eq!(ids(index.deleted[url].iter().map(|u| &u.track)), ["in-c"]);
+        assert!(fetcher.take_downloads().is_empty());
+
+        // the next refresh only handles what is left
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(fetcher.take_downloads(), ["in-d"]);
+        assert_eq!(ids(&index.playlists[url].entries), ["in-a", "in-d"]);
+        assert_eq!(ids(&index.removed[url]), ["in-b"]);
+        assert_eq!(ids(index.deleted[url].iter().map(|u| &u.track)), ["in-c"]);
+        assert_eq!(markers(&b).len(), 1);
+        assert_eq!(markers(&c).len(), 1);
+    }
+
+    #[test]
+    fn test_registry() {
+        let [src1, src2] = [
+            "https://example.com/registry-1",
+            "https://example.com/registry-2",
+        ]
+        .map(source);
+        let (url1, url2) = (src1.url.as_str(), src2.url.as_str());
+        let sources = [src1.clone(), src2.clone()];
+
+        let [mut a, b] = ["rg-a", "rg-b"].map(track);
+        clean(&[&a, &b]);
+
+        let fetcher = ScriptedFetcher::default();
+        let mut index = AppIndex::default();
+
+        a.metadata.duration = Some(180.0);
+        fetcher.set_playlist(url1, &[&a, &b]);
+        fetcher.set_playlist(url2, &[&a]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        // a track that is part of two sources is downloaded and recorded once
+        assert_eq!(fetcher.take_downloads(), ["rg-a", "rg-b"]);
+        let record = &index.tracks["rg-a"];
+        assert_eq!(record.sources.iter().collect::<Vec<_>>(), [url1, url2]);
+        assert_eq!(record.platform, Some(Platform::Generic));
+        assert!(record.first_seen.is_some());
+        assert!(record.downloaded_at.is_some());
+        assert!(record.file_size.is_some_and(|size| size > 0));
+
+        // the stored playlists only refer to the registry
+        let stored = serde_json::to_value(&index).unwrap();
+        assert_eq!(stored["tracks"].as_object().unwrap().len(), 2);
+        assert_eq!(stored["playlists"][url2]["entries"][0]["id"], "rg-a");
+        assert!(stored["playlists"][url2]["entries"][0]["title"].is_null());
+
+        let loaded = serde_json::from_value::<AppIndex>(stored).unwrap();
+        assert_eq!(loaded.playlists[url2].entries[0].idx, 1);
+        assert_eq!(
+            loaded.playlists[url2].entries[0]
+                .resolve(&loaded.tracks)
+                .unwrap()
+                .metadata
+                .duration,
+            Some(180.0)
+        );
+
+        // a is replaced, which is noticed in the first source. the playlist of
+        // the second source points at the new version without refreshing it
+        a.metadata.duration = Some(240.0);
+        fetcher.set_playlist(url1, &[&a, &b]);
+        fetcher.set_playlist(url2, &[&a]);
+        index
+            .refresh_with(&sources[..1], &fetcher, |_| Ok(()))
+            .unwrap();
+
+        assert_eq!(fetcher.take_downloads(), ["rg-a"]);
+        let m3u = std::fs::read_to_string(index.playlists[url2].as_handle().m3u_path).unwrap();
+        assert!(m3u.contains("rg-a/v2/track.mp3"), "{m3u}");
+
+        // ...and isn't downloaded again when the second source is refreshed
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+        assert!(fetcher.take_downloads().is_empty());
+        assert_eq!(index.tracks["rg-a"].track.version, 2);
+
+        // a is removed from the first source, but is still part of the second
+        fetcher.set_playlist(url1, &[&b]);
+        index.refresh_with(&sources, &fetcher, |_| Ok(())).unwrap();
+
+        assert_eq!(
+            index.tracks["rg-a"].sources.iter().collect::<Vec<_>>(),
+            [url2]
+        );
+        assert_eq!(index.tracks["rg-a"].availability, Availability::Available);
+
+        // a comes back to the first source under a new title, which its
+        // record is updated with
+        a.title = "renamed".to_string();
+        fetcher.set_playlist(url1, &[&a, &b]);
+        index
+            .refresh_with(&sources[..1], &fetcher, |_| Ok(()))
+            .unwrap();
+
+        let transition = index.transitions.last().unwrap();
+        assert!(matches!(transition.action, Action::Unremoved));
+        assert_eq!(transition.title, "renamed");
+        assert!(index.removed[url1].is_empty());
+        assert_eq!(index.tracks["rg-a"].track.title, "renamed");
+        assert_eq!(
+            index.tracks["rg-a"].sources.iter().collect::<Vec<_>>(),
+            [url1, url2]
+        );
     }
 }
@@ -0,0 +1,213 @@
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Context, Result};
+
+use crate::{
+    config::{AppConfig, IndexStorage},
+    database,
+    index::Transition,
+    source::failure::FailureReason,
+};
+
+/// An entry in the journal, which is an append-only JSON Lines file with an
+/// entry for every transition that was handled.
+#[serde_with::skip_serializing_none]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub time: DateTime<Utc>,
+    /// The ID of the refresh the transition was handled in
+    pub run_id: String,
+    /// The URL of the source the track is part of
+    pub source_url: String,
+    pub track_id: String,
+    pub uploader: String,
+    pub title: String,
+    /// The name of the action, e.g. `deleted` (see [`crate::index::Action`])
+    pub action: String,
+    pub reason: Option<FailureReason>,
+}
+
+impl From<&Transition> for JournalEntry {
+    fn from(transition: &Transition) -> Self {
+        Self {
+            time: transition.time,
+            run_id: transition.run_id.clone(),
+            source_url: transition.source_url.clone(),
+            track_id: transition.track_id.clone(),
+            uploader: transition.uploader.clone(),
+            title: transition.title.clone(),
+            action: transition.action.name().to_string(),
+            reason: transition.action.reason(),
+        }
+    }
+}
+
+/// Appends the given transitions to the journal at `path`.
+pub fn append(path: &Path, transitions: &[Transition]) -> Result<()> {
+    if transitions.is_empty() {
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .wrap_err("failed to open journal")?;
+
+    let mut lines = String::new();
+
+    // a crash while appending can leave a partial line at the end, which the
+    // new entries must not be appended to
+    if file.metadata()?.len() > 0 {
+        let mut last = [0];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+
+        if last[0] != b'\n' {
+            lines.push('\n');
+        }
+    }
+
+    for transition in transitions {
+        lines.push_str(&serde_json::to_string(&JournalEntry::from(transition))?);
+        lines.push('\n');
+    }
+
+    file.write_all(lines.as_bytes())
+        .wrap_err("failed to write to journal")?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+/// Reads every entry of the journal at `path`, oldest first. An entry is
+/// written twice if saving the index failed after it was journaled, so entries
+/// with the same refresh, time and track as an earlier one are skipped.
+pub fn read(path: &Path) -> Result<Vec<JournalEntry>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let file = std::fs::File::open(path).wrap_err("failed to open journal")?;
+    let mut entries = Vec::new();
+    let mut seen = HashSet::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+
+        // a crash while appending can leave a partial line at the end
+        match serde_json::from_str::<JournalEntry>(&line) {
+            Ok(entry) => {
+                if seen.insert((entry.run_id.clone(), entry.time, entry.track_id.clone())) {
+                    entries.push(entry);
+                }
+            }
+            Err(err) if !line.trim().is_empty() => {
+                warn!("skipping invalid journal entry {:?}: {}", line, err);
+            }
+            Err(_) => {}
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Prints the journal entries of the track with the given ID, or every entry
+/// if no ID is given. When the index is stored in the database, the journal is
+/// read from it instead.
+pub fn print_history(track_id: Option<&str>) -> Result<()> {
+    let config = AppConfig::get();
+
+    let entries = match config.index_storage {
+        IndexStorage::Json => read(&config.paths.journal)?,
+        IndexStorage::Sqlite => database::read_journal()?,
+    };
+
+    for entry in entries
+        .iter()
+        .filter(|entry| track_id.is_none_or(|id| entry.track_id == id))
+    {
+        let reason = entry
+            .reason
+            .map(|reason| format!(" ({reason})"))
+            .unwrap_or_default();
+
+        println!(
+            "{} {}{}: {} - {} [{}] in {} (refresh {})",
+            entry.time.to_rfc3339(),
+            entry.action,
+            reason,
+            entry.uploader,
+            entry.title,
+            entry.track_id,
+            entry.source_url,
+            entry.run_id,
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::Action;
+
+    #[test]
+    fn test_append_and_read() {
+        AppConfig::initialize();
+        AppConfig::get().paths.ensure_all().unwrap();
+
+        let path = AppConfig::get().paths.root.join("journal-test.jsonl");
+        let _ = std::fs::remove_file(&path);
+
+        let transition = |track_id: &str, action| Transition {
+            run_id: "run".to_string(),
+            source_url: "https://example.com/playlist".to_string(),
+            track_id: track_id.to_string(),
+            uploader: "uploader".to_string(),
+            title: "title".to_string(),
+            action,
+            time: Utc::now(),
+        };
+
+        append(&path, &[transition("a", Action::Added)]).unwrap();
+        append(
+            &path,
+            &[transition("b", Action::Deleted(FailureReason::NotFound))],
+        )
+        .unwrap();
+
+        // a partial line is skipped, and doesn't swallow the next entry
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"time\":")
+            .unwrap();
+        append(&path, &[transition("c", Action::Removed)]).unwrap();
+
+        // an entry that was journaled again (because saving the index failed)
+        // is only read once
+        let again = transition("d", Action::Added);
+        append(&path, std::slice::from_ref(&again)).unwrap();
+        append(&path, &[again]).unwrap();
+
+        let entries = read(&path).unwrap();
+
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[3].track_id, "d");
+        assert_eq!(entries[2].track_id, "c");
+        assert_eq!(entries[0].action, "added");
+        assert_eq!(entries[0].reason, None);
+        assert_eq!(entries[1].track_id, "b");
+        assert_eq!(entries[1].reason, Some(FailureReason::NotFound));
+    }
+}
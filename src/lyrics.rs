@@ -0,0 +1,199 @@
+use std::fs;
+
+use color_eyre::eyre::{Context, Result};
+use id3::{TagLike, frame};
+
+use crate::model::TrackHandle;
+
+/// Options for saving lyrics of tracks from a source.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LyricsOptions {
+    /// The subtitle languages to save, in any format yt-dlp's `--sub-langs`
+    /// option accepts (e.g. `en.*`).
+    #[serde(default = "LyricsOptions::default_languages")]
+    pub languages: Vec<String>,
+    /// Whether to save automatically generated captions if there are no
+    /// subtitles.
+    #[serde(default = "LyricsOptions::default_auto_captions")]
+    pub auto_captions: bool,
+    /// Whether to save the description of the track as lyrics (e.g. for
+    /// SoundCloud tracks, where lyrics are usually in the description).
+    #[serde(default)]
+    pub description: bool,
+}
+
+impl LyricsOptions {
+    fn default_languages() -> Vec<String> {
+        vec!["en".to_string()]
+    }
+
+    fn default_auto_captions() -> bool {
+        true
+    }
+
+    /// The yt-dlp arguments that save the lyrics next to the track.
+    pub fn ytdlp_args(&self, handle: &TrackHandle) -> Vec<String> {
+        let mut args = vec![
+            "--write-subs".to_string(),
+            "--sub-langs".to_string(),
+            self.languages.join(","),
+            "--convert-subs".to_string(),
+            "srt".to_string(),
+            "-o".to_string(),
+            // produces `lyrics.<lang>.srt`
+            format!(
+                "subtitle:{}",
+                handle.root_dir.join("lyrics.%(ext)s").display()
+            ),
+        ];
+
+        if self.auto_captions {
+            args.push("--write-auto-subs".to_string());
+        }
+
+        if self.description {
+            args.extend([
+                "--write-description".to_string(),
+                "-o".to_string(),
+                // produces `info.description`
+                format!(
+                    "description:{}",
+                    handle.root_dir.join("info.%(ext)s").display()
+                ),
+            ]);
+        }
+
+        args
+    }
+}
+
+/// A set of lyrics saved next to a track.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SavedLyrics {
+    /// The language of the lyrics according to yt-dlp, or `description` for
+    /// lyrics taken from the description.
+    pub label: String,
+    pub text: String,
+}
+
+/// Reads the lyrics that were saved next to a track.
+pub fn read_saved(handle: &TrackHandle) -> Result<Vec<SavedLyrics>> {
+    let mut lyrics = Vec::new();
+
+    for entry in fs::read_dir(&handle.root_dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+
+        let (label, text) = if let Some(lang) = name
+            .strip_prefix("lyrics.")
+            .and_then(|name| name.strip_suffix(".srt"))
+        {
+            let srt = fs::read_to_string(handle.root_dir.join(&name))?;
+            (lang.to_string(), srt_to_text(&srt))
+        } else if name == "info.description" {
+            let description = fs::read_to_string(handle.root_dir.join(&name))?;
+            ("description".to_string(), description.trim().to_string())
+        } else {
+            continue;
+        };
+
+        if !text.is_empty() {
+            lyrics.push(SavedLyrics { label, text });
+        }
+    }
+
+    // read_dir doesn't guarantee any order
+    lyrics.sort_by(|l1, l2| l1.label.cmp(&l2.label));
+
+    Ok(lyrics)
+}
+
+/// Embeds lyrics into an MP3 file as `USLT` frames, replacing any lyrics that
+/// were embedded before.
+pub fn embed(handle: &TrackHandle, lyrics: &[SavedLyrics]) -> Result<()> {
+    let mut metadata = id3::Tag::read_from_path(&handle.track_path)
+        .wrap_err("failed to read track metadata to write lyrics to")?;
+
+    metadata.remove_all_lyrics();
+
+    for SavedLyrics { label, text } in lyrics {
+        metadata.add_frame(frame::Lyrics {
+            // yt-dlp's language codes are not ISO-639-2, so the language is
+            // stored in the description instead
+            lang: "XXX".to_string(),
+            description: label.clone(),
+            text: text.clone(),
+        });
+    }
+
+    metadata
+        .write_to_path(&handle.track_path, id3::Version::Id3v24)
+        .wrap_err("failed to write lyrics")?;
+
+    Ok(())
+}
+
+/// Converts SRT subtitles into plain text. Automatically generated captions
+/// repeat each line while it scrolls by, so consecutive duplicate lines are
+/// dropped.
+pub fn srt_to_text(srt: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+
+    for line in srt.lines() {
+        let line = line.trim();
+
+        // cue numbers and timings
+        if line.is_empty() || line.chars().all(|c| c.is_ascii_digit()) || line.contains("-->") {
+            continue;
+        }
+
+        let line = strip_tags(line);
+
+        if line.is_empty() || lines.last().is_some_and(|last| last == &line) {
+            continue;
+        }
+
+        lines.push(line);
+    }
+
+    lines.join("\n")
+}
+
+/// Removes formatting tags like `<i>` and `<font color="...">`.
+fn strip_tags(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut in_tag = false;
+
+    for c in line.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => stripped.push(c),
+            _ => {}
+        }
+    }
+
+    stripped.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_srt_to_text() {
+        let srt = "1\n\
+                   00:00:01,000 --> 00:00:03,000\n\
+                   <i>first line</i>\n\
+                   \n\
+                   2\n\
+                   00:00:03,000 --> 00:00:05,000\n\
+                   first line\n\
+                   second line\n\
+                   \n\
+                   3\n\
+                   00:00:05,000 --> 00:00:07,000\n\
+                   <font color=\"#ffffff\">third line</font>\n";
+
+        assert_eq!(srt_to_text(srt), "first line\nsecond line\nthird line");
+    }
+}
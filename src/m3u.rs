@@ -1,38 +1,74 @@
-use std::fs;
+use std::{collections::HashMap, fs, path::Path};
 
 use color_eyre::eyre::Result;
 
-use crate::model::Playlist;
+use crate::{index::IndexedPlaylist, model::Track, registry::TrackRecord};
 
-#[instrument(skip(playlist))]
-pub fn write_playlist(playlist: &Playlist) -> Result<()> {
+/// Writes the playlist definitions of a playlist and its sections. The files
+/// of every track are looked up in the registry, so a track that was replaced
+/// in another source points at its latest version.
+#[instrument(skip(playlist, registry))]
+pub fn write_playlist(
+    playlist: &IndexedPlaylist,
+    registry: &HashMap<String, TrackRecord>,
+) -> Result<()> {
     trace!("writing playlist {:?} ({})", playlist.title, playlist.id);
 
-    let playlist_handle = playlist.as_handle();
+    let entries = playlist
+        .entries
+        .iter()
+        .filter_map(|t| t.resolve(registry))
+        .collect::<Vec<_>>();
 
-    if playlist_handle.m3u_path.exists() {
-        trace!("deleting old playlist definition");
-        fs::remove_file(&playlist_handle.m3u_path)?;
+    let mut sorted = entries.iter().collect::<Vec<_>>();
+    sorted.sort_by(|t1, t2| t1.idx.cmp(&t2.idx));
+
+    write_m3u(&playlist.as_handle().m3u_path, sorted)?;
+
+    for section in &playlist.sections {
+        trace!("writing section {:?} ({})", section.title, section.id);
+
+        let tracks = section
+            .track_ids
+            .iter()
+            .filter_map(|id| entries.iter().find(|t| &t.id == id));
+
+        write_m3u(&section.as_handle().m3u_path, tracks)?;
     }
 
-    let mut sorted = playlist.entries.iter().collect::<Vec<_>>();
-    sorted.sort_by(|t1, t2| t1.idx.cmp(&t2.idx));
+    Ok(())
+}
 
-    let contents = playlist
-        .entries
-        .iter()
+fn write_m3u<'a>(path: &Path, tracks: impl IntoIterator<Item = &'a Track>) -> Result<()> {
+    if path.exists() {
+        trace!("deleting old playlist definition");
+        fs::remove_file(path)?;
+    }
+
+    let contents = tracks
+        .into_iter()
         .map(|track| {
-            track
-                .as_handle()
-                .playlist_entry_path()
-                .to_string_lossy()
-                .to_string()
-                + "\n"
+            // extended M3U info, which players show instead of the file name.
+            // the duration is -1 if it is unknown
+            let duration = track.metadata.duration.map_or(-1, |d| d.round() as i64);
+
+            format!(
+                "#EXTINF:{},{} - {}\n{}\n",
+                duration,
+                track.uploader,
+                track.title.replace('\n', " "),
+                track
+                    .playlist_handle()
+                    .playlist_entry_path()
+                    .to_string_lossy()
+            )
         })
         .collect::<String>();
 
+    let contents = format!("#EXTM3U\n{contents}");
+
     trace!("writing playlist definition");
-    fs::write(&playlist_handle.m3u_path, contents)?;
+    fs::write(path, contents)?;
 
     Ok(())
 }
@@ -15,11 +15,19 @@ extern crate tracing;
 extern crate serde;
 
 pub mod config;
+pub mod database;
 pub mod index;
+pub mod journal;
+pub mod lyrics;
 pub mod m3u;
+pub mod migrations;
 pub mod model;
+pub mod regenerate;
+pub mod registry;
 pub mod retry;
 pub mod source;
+pub mod sponsorblock;
+pub mod tags;
 pub mod util;
 
 #[global_allocator]
@@ -42,11 +50,20 @@ fn main() -> Result<()> {
     color_eyre::install()?;
 
     ctrlc::set_handler(|| {
-        info!("received termination signal, exiting");
-        if AppIndex::is_refreshing() {
-            warn!("index is currently refreshing! all progress except downloads will be lost");
+        if !AppIndex::is_refreshing() {
+            info!("received termination signal, exiting");
+            std::process::exit(0);
+        }
+
+        // the refresh saves its progress and stops on its own
+        if AppIndex::request_shutdown() {
+            info!("received termination signal, stopping the refresh");
+        } else {
+            warn!(
+                "received termination signal again, exiting without saving the refresh in progress"
+            );
+            std::process::exit(1);
         }
-        std::process::exit(0);
     })
     .unwrap();
 
@@ -66,13 +83,32 @@ fn main() -> Result<()> {
             .unwrap_or_else(|| now() + chrono::Duration::try_hours(24).unwrap())
     };
 
+    if std::env::args().nth(1).as_deref() == Some("--check") {
+        return migrations::check();
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("history") {
+        let track_id = std::env::args().nth(2);
+        return journal::print_history(track_id.as_deref());
+    }
+
     let mut index = AppIndex::load()?;
 
+    if std::env::args().nth(1).as_deref() == Some("regenerate") {
+        info!("regenerating playback files from masters");
+        return regenerate::regenerate_playback_files(&mut index);
+    }
+
     const RETRY_OPTIONS: RetryOptions = RetryOptions::new().with_policy(RetryPolicy::Immediate);
 
     loop {
         retry_options_with(RETRY_OPTIONS, || index.refresh(), "failed to refresh")?;
 
+        if AppIndex::is_shutdown_requested() {
+            info!("refresh stopped, exiting");
+            return Ok(());
+        }
+
         let now = now();
         let next = next_refresh();
         debug!("next refresh at: {:?}", next);
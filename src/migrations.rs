@@ -0,0 +1,351 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt, fs,
+    path::Path,
+};
+
+use color_eyre::eyre::{Context, Result, bail};
+use serde_json::{Map, Value, json};
+
+use crate::{
+    config::{AppConfig, IndexStorage},
+    database,
+};
+
+/// The version of the index schema written by this version of acad. This must
+/// be increased whenever a migration is added.
+pub const SCHEMA_VERSION: u64 = 2;
+
+/// The key of the schema version in `index.json`. Indexes without it predate
+/// versioning, and have version 0.
+pub const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// A step that migrates the index to the next schema version.
+#[derive(Debug)]
+pub struct Migration {
+    /// The schema version the index has after this migration
+    pub version: u64,
+    pub description: &'static str,
+    apply: fn(&mut Map<String, Value>) -> Result<()>,
+}
+
+/// Every migration, in the order they are applied.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "add the lists of unavailable and proxied tracks to indexes that predate them",
+        apply: add_missing_lists,
+    },
+    Migration {
+        version: 2,
+        description: "move the tracks of every playlist into the track registry",
+        apply: add_track_registry,
+    },
+];
+
+/// The index was written by a newer version of acad, which we can't read
+/// without risking losing data.
+#[derive(Debug)]
+pub struct NewerSchema(pub u64);
+
+impl fmt::Display for NewerSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the index has schema version {}, but this version of acad only supports up to {}",
+            self.0, SCHEMA_VERSION
+        )
+    }
+}
+
+impl Error for NewerSchema {}
+
+/// Returns the schema version of a serialized index.
+pub fn schema_version(index: &Value) -> u64 {
+    index
+        .get(SCHEMA_VERSION_KEY)
+        .and_then(Value::as_u64)
+        .unwrap_or(0)
+}
+
+/// Returns the migrations that have to be applied to an index with the given
+/// schema version.
+pub fn pending(version: u64) -> Result<Vec<&'static Migration>, NewerSchema> {
+    if version > SCHEMA_VERSION {
+        return Err(NewerSchema(version));
+    }
+
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+/// Migrates a serialized index to the current schema version. Returns the
+/// migrations that were applied.
+pub fn migrate(index: &mut Value) -> Result<Vec<&'static Migration>> {
+    let pending = pending(schema_version(index))?;
+
+    let Some(fields) = index.as_object_mut() else {
+        bail!("the index is not a JSON object");
+    };
+
+    for migration in &pending {
+        (migration.apply)(fields).wrap_err_with(|| {
+            format!(
+                "failed to migrate index to schema version {}",
+                migration.version
+            )
+        })?;
+        fields.insert(SCHEMA_VERSION_KEY.to_string(), migration.version.into());
+    }
+
+    Ok(pending)
+}
+
+/// Reports the schema version of the index and the migrations that would be
+/// applied to it the next time it is loaded, without changing anything.
+pub fn check() -> Result<()> {
+    let config = AppConfig::get();
+
+    match config.index_storage {
+        IndexStorage::Json => check_json(&config.paths.index),
+        IndexStorage::Sqlite => database::check(),
+    }
+}
+
+/// Reports the schema version of `index.json` and the migrations that would be
+/// applied to it.
+pub fn check_json(path: &Path) -> Result<()> {
+    if !path.exists() {
+        println!("there is no index at {}", path.display());
+        return Ok(());
+    }
+
+    let index = serde_json::from_str::<Value>(&fs::read_to_string(path)?)
+        .wrap_err("failed to parse index")?;
+    let version = schema_version(&index);
+
+    println!(
+        "{} has schema version {} (the current version is {})",
+        path.display(),
+        version,
+        SCHEMA_VERSION
+    );
+
+    let pending = pending(version)?;
+
+    if pending.is_empty() {
+        println!("no migrations need to run");
+    }
+
+    for migration in pending {
+        println!(
+            "would migrate to version {}: {}",
+            migration.version, migration.description
+        );
+    }
+
+    Ok(())
+}
+
+fn add_missing_lists(index: &mut Map<String, Value>) -> Result<()> {
+    for key in ["deleted", "removed", "restricted", "proxied"] {
+        index
+            .entry(key)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+
+    Ok(())
+}
+
+fn add_track_registry(index: &mut Map<String, Value>) -> Result<()> {
+    // this is written against the JSON of the index instead of the index
+    // types, so later changes to them don't change what this migration does
+    let mut records = BTreeMap::<String, Record>::new();
+
+    // the unavailable lists go first, so a track that is still part of
+    // another source ends up available
+    for key in ["removed", "deleted", "restricted"] {
+        for (_, tracks) in lists_mut(index, key)? {
+            for track in tracks.as_array_mut().into_iter().flatten() {
+                let (id, manifest) = split_track(track)?;
+                let record = records.entry(id).or_insert_with(|| Record::new(manifest));
+
+                if key != "removed" {
+                    let reason = track.get("reason").cloned().unwrap_or(Value::Null);
+                    // the lists are named after the status of their tracks
+                    record.availability = json!({ "status": key, "reason": reason });
+                }
+            }
+        }
+    }
+
+    for (url, playlist) in lists_mut(index, "playlists")? {
+        let entries = playlist.get_mut("entries").and_then(Value::as_array_mut);
+
+        for track in entries.into_iter().flatten() {
+            // the manifest of a track in several sources is the one from the
+            // last of them
+            let (id, manifest) = split_track(track)?;
+            let record = records
+                .entry(id)
+                .or_insert_with(|| Record::new(manifest.clone()));
+
+            record.track = manifest;
+            record.sources.insert(url.clone());
+            record.availability = json!({ "status": "available" });
+        }
+    }
+
+    // when the tracks were first seen isn't known, and the rest of what the
+    // registry knows about a track (its platform and the size of its file) is
+    // filled in the next time its source is refreshed
+    index.insert("tracks".to_string(), serde_json::to_value(records)?);
+
+    Ok(())
+}
+
+/// A record of the track registry, as it was written by schema version 2.
+#[derive(Serialize)]
+struct Record {
+    track: Value,
+    sources: BTreeSet<String>,
+    availability: Value,
+}
+
+impl Record {
+    fn new(track: Value) -> Self {
+        Self {
+            track,
+            sources: BTreeSet::new(),
+            availability: json!({ "status": "available" }),
+        }
+    }
+}
+
+/// Returns the lists (keyed by source URL) under the given key of the index.
+fn lists_mut<'a>(
+    index: &'a mut Map<String, Value>,
+    key: &str,
+) -> Result<impl Iterator<Item = (&'a String, &'a mut Value)>> {
+    let Some(lists) = index.get_mut(key).and_then(Value::as_object_mut) else {
+        bail!("{key} is not a JSON object");
+    };
+
+    Ok(lists.iter_mut())
+}
+
+/// Splits a track of a version 1 list into its manifest, which moves into the
+/// registry, and the reference to it (with its position in the list, and the
+/// reason it is unavailable) that stays in the list. Returns the ID of the
+/// track and its manifest.
+fn split_track(track: &mut Value) -> Result<(String, Value)> {
+    let Some(fields) = track.as_object_mut() else {
+        bail!("a track is not a JSON object");
+    };
+    let Some(id) = fields.get("id").and_then(Value::as_str).map(str::to_string) else {
+        bail!("a track has no ID");
+    };
+
+    let mut manifest = std::mem::take(fields);
+    let idx = manifest
+        .insert("playlist_index".to_string(), Value::Null)
+        .unwrap_or(Value::Null);
+
+    fields.insert("id".to_string(), id.clone().into());
+    fields.insert("playlist_index".to_string(), idx);
+    if let Some(reason) = manifest.remove("reason") {
+        fields.insert("reason".to_string(), reason);
+    }
+
+    Ok((id, Value::Object(manifest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{index::AppIndex, registry::Availability, source::failure::FailureReason};
+
+    #[test]
+    fn test_migrate() {
+        AppConfig::initialize();
+
+        // an index from before the schema was versioned, which was also
+        // written before tracks could be restricted
+        let mut index =
+            serde_json::from_str::<Value>(r#"{ "playlists": {}, "deleted": {}, "removed": {} }"#)
+                .unwrap();
+
+        assert_eq!(schema_version(&index), 0);
+        assert!(serde_json::from_value::<AppIndex>(index.clone()).is_err());
+
+        let applied = migrate(&mut index).unwrap();
+
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(schema_version(&index), SCHEMA_VERSION);
+        assert!(serde_json::from_value::<AppIndex>(index.clone()).is_ok());
+
+        // migrating again does nothing
+        assert!(migrate(&mut index).unwrap().is_empty());
+
+        // the tracks of a version 1 index are moved into the registry, and
+        // a track that is part of two lists only gets one record
+        let track = |id: &str, idx: usize| {
+            format!(
+                r#"{{ "id": "{id}", "uploader": "uploader", "title": "{id}",
+                    "original_url": "https://example.com/{id}", "playlist_index": {idx} }}"#
+            )
+        };
+        let mut index = serde_json::from_str::<Value>(&format!(
+            r#"{{
+                "schema_version": 1,
+                "playlists": {{
+                    "https://example.com/playlist": {{
+                        "id": "playlist", "title": "playlist",
+                        "entries": [{}, {}],
+                        "original_url": "https://example.com/playlist", "playlist_count": 2
+                    }}
+                }},
+                "deleted": {{ "https://example.com/other": [{}] }},
+                "removed": {{ "https://example.com/other": [{}] }},
+                "restricted": {{}},
+                "proxied": {{}}
+            }}"#,
+            track("a", 1),
+            track("b", 2),
+            track("c", 3).replace('}', r#", "reason": "removed_by_uploader" }"#),
+            track("a", 7),
+        ))
+        .unwrap();
+
+        assert_eq!(migrate(&mut index).unwrap().len(), 1);
+
+        let migrated = serde_json::from_value::<AppIndex>(index.clone()).unwrap();
+        let url = "https://example.com/playlist";
+
+        assert_eq!(migrated.tracks.len(), 3);
+        assert_eq!(
+            migrated.tracks["a"].sources.iter().collect::<Vec<_>>(),
+            [url]
+        );
+        assert_eq!(migrated.tracks["a"].first_seen, None);
+        assert_eq!(migrated.playlists[url].entries[1].id, "b");
+        assert_eq!(migrated.removed["https://example.com/other"][0].idx, 7);
+        assert_eq!(migrated.tracks["a"].availability, Availability::Available);
+        assert_eq!(
+            migrated.tracks["c"].availability,
+            Availability::Deleted(Some(FailureReason::RemovedByUploader))
+        );
+        assert_eq!(
+            migrated.deleted["https://example.com/other"][0].reason,
+            Some(FailureReason::RemovedByUploader)
+        );
+
+        index[SCHEMA_VERSION_KEY] = (SCHEMA_VERSION + 1).into();
+        assert!(
+            migrate(&mut index)
+                .unwrap_err()
+                .downcast_ref::<NewerSchema>()
+                .is_some()
+        );
+    }
+}
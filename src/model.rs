@@ -1,8 +1,8 @@
-use std::{fmt, marker::PhantomData, path::PathBuf};
+use std::{collections::HashSet, fmt, marker::PhantomData, path::PathBuf};
 
 use serde::de::{Deserialize, Deserializer, SeqAccess, Visitor};
 
-use crate::config::AppConfig;
+use crate::{config::AppConfig, sponsorblock::AppliedSegment};
 
 fn skip_nulls<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
 where
@@ -36,26 +36,195 @@ where
     deserializer.deserialize_seq(SkipNulls(PhantomData))
 }
 
+/// An entry of a playlist manifest. Some feeds (e.g. SoundCloud likes and
+/// reposts) contain whole playlists as entries alongside regular tracks.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Playlist {
+        #[serde(deserialize_with = "skip_nulls")]
+        entries: Vec<Entry>,
+    },
+    Track(Track),
+}
+
+impl Entry {
+    fn flatten_into(self, tracks: &mut Vec<Track>) {
+        match self {
+            Self::Playlist { entries } => {
+                for entry in entries {
+                    entry.flatten_into(tracks);
+                }
+            }
+            Self::Track(track) => tracks.push(track),
+        }
+    }
+}
+
+/// Deserializes the entries of a playlist, skipping nulls and flattening any
+/// nested playlists into a single list of tracks.
+///
+/// If any nested playlists were found, duplicate tracks are dropped and the
+/// tracks are renumbered in the order they appear, since the indices of nested
+/// tracks are only relative to their own playlist.
+fn flatten_entries<'de, D>(deserializer: D) -> Result<Vec<Track>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let entries = skip_nulls::<D, Entry>(deserializer)?;
+
+    let nested = entries
+        .iter()
+        .any(|entry| matches!(entry, Entry::Playlist { .. }));
+
+    let mut tracks = Vec::with_capacity(entries.len());
+    for entry in entries {
+        entry.flatten_into(&mut tracks);
+    }
+
+    if nested {
+        let mut seen = HashSet::new();
+        tracks.retain(|track| seen.insert(track.id.clone()));
+
+        for (idx, track) in tracks.iter_mut().enumerate() {
+            track.idx = idx + 1;
+        }
+    }
+
+    Ok(tracks)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Playlist {
     pub id: String,
     pub title: String,
-    #[serde(deserialize_with = "skip_nulls")]
+    #[serde(deserialize_with = "flatten_entries")]
     pub entries: Vec<Track>,
     #[serde(rename = "original_url")]
     pub url: String,
     #[serde(rename = "playlist_count")]
     pub len: usize,
+    /// Subsets of this playlist that get their own playlist definitions (e.g.
+    /// the tabs of a YouTube channel)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sections: Vec<PlaylistSection>,
+}
+
+/// A named subset of a playlist's tracks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaylistSection {
+    pub id: String,
+    pub title: String,
+    /// The IDs of the tracks in this section, in playlist order
+    pub track_ids: Vec<String>,
+}
+
+impl PlaylistSection {
+    pub fn as_handle(&self) -> PlaylistHandle {
+        PlaylistHandle::from_id(&self.id)
+    }
+}
+
+#[derive(Deserialize)]
+struct ManifestType {
+    #[serde(rename = "_type", default)]
+    kind: Option<String>,
 }
 
 impl Playlist {
+    /// Parses a manifest produced by `yt-dlp -J`.
+    ///
+    /// If the manifest describes a single track instead of a playlist, the
+    /// track is wrapped in a playlist containing only that track.
+    pub fn from_manifest(manifest: &str) -> serde_json::Result<Self> {
+        let ManifestType { kind } = serde_json::from_str(manifest)?;
+
+        if kind.as_deref() == Some("playlist") {
+            return serde_json::from_str(manifest);
+        }
+
+        trace!(
+            "manifest is not a playlist ({:?}), treating it as a single track",
+            kind
+        );
+
+        let track = serde_json::from_str::<SingleTrack>(manifest)?;
+
+        Ok(Self::from_single_track(track))
+    }
+
+    /// Creates a playlist containing only the given track.
+    pub fn from_single_track(track: SingleTrack) -> Self {
+        Self {
+            id: track.id.clone(),
+            title: track.title.clone(),
+            url: track.url.clone(),
+            len: 1,
+            entries: vec![track.with_idx(1)],
+            sections: Vec::new(),
+        }
+    }
+
     pub fn as_handle(&self) -> PlaylistHandle {
-        let mut playlist_definition_path = AppConfig::get().paths.playlists.join(&self.id);
-        playlist_definition_path.set_extension("m3u");
+        PlaylistHandle::from_id(&self.id)
+    }
+}
 
-        PlaylistHandle {
-            m3u_path: playlist_definition_path,
+/// A playlist manifest produced by `yt-dlp -J --flat-playlist`. The entries only
+/// contain enough information to identify each track.
+#[derive(Debug, Deserialize)]
+pub struct FlatPlaylist {
+    pub id: String,
+    pub title: String,
+    #[serde(deserialize_with = "skip_nulls")]
+    pub entries: Vec<FlatEntry>,
+    #[serde(rename = "original_url")]
+    pub url: String,
+    #[serde(rename = "playlist_count")]
+    pub len: usize,
+}
+
+impl FlatPlaylist {
+    /// Parses a manifest produced by `yt-dlp -J --flat-playlist`. Returns
+    /// `None` if the manifest describes a single track, in which case it is a
+    /// full manifest (yt-dlp has nothing to flatten).
+    pub fn from_manifest(manifest: &str) -> serde_json::Result<Option<Self>> {
+        let ManifestType { kind } = serde_json::from_str(manifest)?;
+
+        if kind.as_deref() != Some("playlist") {
+            return Ok(None);
         }
+
+        serde_json::from_str(manifest).map(Some)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FlatEntry {
+    pub id: Option<String>,
+    pub url: String,
+    #[serde(rename = "playlist_index")]
+    pub idx: Option<usize>,
+    pub ie_key: Option<String>,
+    /// Only some platforms list the title and duration of each track
+    pub title: Option<String>,
+    pub duration: Option<f64>,
+}
+
+impl FlatEntry {
+    /// Whether this entry (probably) refers to a playlist instead of a track.
+    /// Flat listings don't expand nested playlists, so these can't be handled
+    /// without fetching the full manifest.
+    pub fn is_playlist(&self) -> bool {
+        const IE_MARKERS: &[&str] = &["Set", "Playlist", "Tab", "Album", "User"];
+        const URL_MARKERS: &[&str] = &["/sets/", "/album/", "list="];
+
+        self.id.is_none()
+            || self
+                .ie_key
+                .as_deref()
+                .is_some_and(|ie| IE_MARKERS.iter().any(|marker| ie.contains(marker)))
+            || URL_MARKERS.iter().any(|marker| self.url.contains(marker))
     }
 }
 
@@ -64,6 +233,17 @@ pub struct PlaylistHandle {
     pub m3u_path: PathBuf,
 }
 
+impl PlaylistHandle {
+    pub fn from_id(id: &str) -> Self {
+        let mut playlist_definition_path = AppConfig::get().paths.playlists.join(id);
+        playlist_definition_path.set_extension("m3u");
+
+        Self {
+            m3u_path: playlist_definition_path,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RawTrack<T> {
     pub id: String,
@@ -74,6 +254,58 @@ pub struct RawTrack<T> {
     #[serde(rename = "playlist_index")]
     // this field is null unless this track was part of a playlist manifest
     pub idx: T,
+    /// The extension of the downloaded audio file. This is `None` until the
+    /// track is downloaded, and for tracks downloaded before the audio format
+    /// was configurable (which are always MP3s).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_ext: Option<String>,
+    /// The extension of the archival master (the original stream the playback
+    /// file was converted from). This is `None` if no master was kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub master_ext: Option<String>,
+    /// The extension of the archived video. This is `None` if the video was
+    /// not archived.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_ext: Option<String>,
+    /// The SponsorBlock segments that were applied to the downloaded file.
+    /// This is `None` if SponsorBlock was not enabled when the track was
+    /// downloaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sponsor_segments: Option<Vec<AppliedSegment>>,
+    /// The version of the track that was downloaded last. This is increased
+    /// every time the uploader replaces the audio of the track, and every
+    /// version is kept in its own directory.
+    #[serde(default = "first_version", skip_serializing_if = "is_first_version")]
+    pub version: u32,
+    // boxed because most of the metadata is rarely used, but tracks are moved
+    // around a lot
+    #[serde(flatten)]
+    pub metadata: Box<TrackMetadata>,
+}
+
+/// Metadata about a track from its manifest. Every field is optional, since
+/// not every platform provides all of them (and tracks indexed before they
+/// were recorded don't have any of them).
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrackMetadata {
+    /// The date the track was uploaded, as `YYYYMMDD`
+    pub upload_date: Option<String>,
+    /// The duration of the track in seconds
+    pub duration: Option<f64>,
+    pub description: Option<String>,
+    pub genre: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub uploader_id: Option<String>,
+    pub uploader_url: Option<String>,
+    #[serde(rename = "thumbnail")]
+    pub thumbnail_url: Option<String>,
+    pub license: Option<String>,
+    pub view_count: Option<u64>,
+    pub like_count: Option<u64>,
+    /// The size of the downloaded file in bytes, which is only known for some
+    /// formats
+    pub filesize: Option<u64>,
 }
 
 /// A Track that is part of a playlist
@@ -81,28 +313,169 @@ pub type Track = RawTrack<usize>;
 /// A Track that may or may not be part of a playlist
 pub type SingleTrack = RawTrack<()>;
 
-impl Track {
+impl<T> RawTrack<T> {
+    /// Converts this track into a track with the given playlist index.
+    pub fn with_idx<U>(self, idx: U) -> RawTrack<U> {
+        RawTrack {
+            id: self.id,
+            uploader: self.uploader,
+            title: self.title,
+            url: self.url,
+            idx,
+            file_ext: self.file_ext,
+            master_ext: self.master_ext,
+            video_ext: self.video_ext,
+            sponsor_segments: self.sponsor_segments,
+            version: self.version,
+            metadata: self.metadata,
+        }
+    }
+
     pub fn as_handle(&self) -> TrackHandle {
-        let root_dir = AppConfig::get().paths.audio.join(&self.id);
-        let track_path = root_dir.join("track.mp3");
+        let root_dir = Self::version_dir(&self.id, self.version);
+        let track_path = root_dir
+            .join("track")
+            .with_extension(self.file_ext.as_deref().unwrap_or(DEFAULT_FILE_EXT));
+        let master_path = self
+            .master_ext
+            .as_ref()
+            .map(|ext| root_dir.join("master").with_extension(ext));
+        let video_path = self
+            .video_ext
+            .as_ref()
+            .map(|ext| root_dir.join("video").with_extension(ext));
         let album_art_path = root_dir.join("cover.jpg");
 
         TrackHandle {
             root_dir,
             track_path,
+            master_path,
+            video_path,
             album_art_path,
         }
     }
+
+    /// The directory a version of a track is saved in. The first version is
+    /// saved in the track's directory, and later versions in `v<version>`
+    /// directories inside of it.
+    fn version_dir(id: &str, version: u32) -> PathBuf {
+        let dir = AppConfig::get().paths.audio.join(id);
+
+        if version > 1 {
+            dir.join(format!("v{version}"))
+        } else {
+            dir
+        }
+    }
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+fn is_first_version(version: &u32) -> bool {
+    *version == 1
 }
 
+impl Track {
+    /// Returns the handle of the version of this track that playlists point
+    /// at, which is the version pinned in the config or the latest version.
+    pub fn playlist_handle(&self) -> TrackHandle {
+        match AppConfig::get().pinned_versions.get(&self.id) {
+            Some(&version) if version < self.version => {
+                // older versions may have been downloaded in another format
+                let mut handle = RawTrack {
+                    version,
+                    file_ext: None,
+                    ..self.clone()
+                }
+                .as_handle();
+
+                if let Some(ext) = handle.find_file_ext() {
+                    handle.track_path.set_extension(ext);
+                }
+
+                handle
+            }
+            _ => self.as_handle(),
+        }
+    }
+
+    /// Looks for the latest version of this track that was downloaded.
+    pub fn find_latest_version(&self) -> u32 {
+        let Ok(entries) = std::fs::read_dir(Self::version_dir(&self.id, 1)) else {
+            return 1;
+        };
+
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str()?.strip_prefix('v')?.parse().ok())
+            .filter(|&version| {
+                RawTrack {
+                    version,
+                    ..self.clone()
+                }
+                .as_handle()
+                .find_file_ext()
+                .is_some()
+            })
+            .max()
+            .unwrap_or(1)
+    }
+}
+
+/// The extension of tracks that don't have one recorded.
+pub const DEFAULT_FILE_EXT: &str = "mp3";
+
+/// The extensions yt-dlp can produce when extracting audio.
+pub const AUDIO_EXTENSIONS: &[&str] = &["mp3", "opus", "m4a", "flac", "ogg", "aac", "wav"];
+
 #[derive(Debug)]
 pub struct TrackHandle {
     pub root_dir: PathBuf,
     pub track_path: PathBuf,
+    pub master_path: Option<PathBuf>,
+    pub video_path: Option<PathBuf>,
     pub album_art_path: PathBuf,
 }
 
 impl TrackHandle {
+    /// Looks for a downloaded audio file in the track's directory and returns
+    /// its extension, regardless of the extension recorded for the track.
+    pub fn find_file_ext(&self) -> Option<String> {
+        self.find_exts("track")
+            .into_iter()
+            .find(|ext| AUDIO_EXTENSIONS.contains(&ext.as_str()))
+    }
+
+    /// Looks for an archival master in the track's directory and returns its
+    /// extension.
+    pub fn find_master_ext(&self) -> Option<String> {
+        self.find_exts("master").into_iter().next()
+    }
+
+    /// Looks for an archived video in the track's directory and returns its
+    /// extension.
+    pub fn find_video_ext(&self) -> Option<String> {
+        self.find_exts("video").into_iter().next()
+    }
+
+    /// Returns the extensions of the files in the track's directory with the
+    /// given name, ignoring yt-dlp's temporary files.
+    pub fn find_exts(&self, name: &str) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(&self.root_dir) else {
+            return Vec::new();
+        };
+
+        entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| PathBuf::from(entry.file_name()))
+            .filter(|path| path.file_stem().is_some_and(|stem| stem == name))
+            .filter_map(|path| Some(path.extension()?.to_str()?.to_string()))
+            .filter(|ext| !matches!(ext.as_str(), "part" | "ytdl" | "temp"))
+            .collect()
+    }
+
     /// Returns the path of this track relative to the MPD music directory.
     ///
     /// If `AppConfig.mpd_music_dir` is None, we will assume that MPD's
@@ -144,6 +517,189 @@ mod tests {
         assert_eq!(actual.a, expected);
     }
 
+    #[test]
+    fn test_single_track_manifest() {
+        let input = r#"{
+            "_type": "video",
+            "id": "1234567890",
+            "uploader": "uploader",
+            "title": "title",
+            "original_url": "https://example.com/fakeuser/track-slug",
+            "playlist_index": null
+        }"#;
+
+        let playlist = Playlist::from_manifest(input).unwrap();
+
+        assert_eq!(playlist.id, "1234567890");
+        assert_eq!(playlist.len, 1);
+        assert_eq!(playlist.entries.len(), 1);
+        assert_eq!(playlist.entries[0].idx, 1);
+        assert_eq!(playlist.entries[0].url, playlist.url);
+    }
+
+    #[test]
+    fn test_track_metadata() {
+        let input = r#"{
+            "id": "1234567890",
+            "uploader": "uploader",
+            "title": "title",
+            "original_url": "https://example.com/fakeuser/track-slug",
+            "playlist_index": 1,
+            "upload_date": "20240131",
+            "duration": 213,
+            "genre": null,
+            "tags": ["tag 1", "tag 2"],
+            "view_count": 1000,
+            "formats": []
+        }"#;
+
+        let track: Track = serde_json::from_str(input).unwrap();
+
+        assert_eq!(track.metadata.upload_date.as_deref(), Some("20240131"));
+        assert_eq!(track.metadata.duration, Some(213.0));
+        assert_eq!(track.metadata.genre, None);
+        assert_eq!(track.metadata.tags.as_ref().unwrap().len(), 2);
+        assert_eq!(track.metadata.view_count, Some(1000));
+
+        // missing metadata is not written to the index
+        let serialized = serde_json::to_string(&track).unwrap();
+        assert!(serialized.contains("upload_date"));
+        assert!(!serialized.contains("genre"));
+
+        // tracks indexed before the metadata was recorded still load
+        let input = r#"{
+            "id": "1234567890",
+            "uploader": "uploader",
+            "title": "title",
+            "original_url": "https://example.com/fakeuser/track-slug",
+            "playlist_index": 1
+        }"#;
+
+        let track: Track = serde_json::from_str(input).unwrap();
+
+        assert!(track.metadata.duration.is_none());
+        assert!(track.file_ext.is_none());
+    }
+
+    #[test]
+    fn test_playlist_manifest() {
+        let input = r#"{
+            "_type": "playlist",
+            "id": "playlist",
+            "title": "title",
+            "original_url": "https://example.com/fakeuser/sets/playlist",
+            "playlist_count": 2,
+            "entries": [
+                {
+                    "id": "1",
+                    "uploader": "uploader",
+                    "title": "first",
+                    "original_url": "https://example.com/fakeuser/first",
+                    "playlist_index": 1
+                },
+                null
+            ]
+        }"#;
+
+        let playlist = Playlist::from_manifest(input).unwrap();
+
+        assert_eq!(playlist.id, "playlist");
+        assert_eq!(playlist.len, 2);
+        assert_eq!(playlist.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_nested_playlist_manifest() {
+        let input = r#"{
+            "_type": "playlist",
+            "id": "1234",
+            "title": "user (Likes)",
+            "original_url": "https://example.com/fakeuser/likes",
+            "playlist_count": 2,
+            "entries": [
+                {
+                    "id": "1",
+                    "uploader": "uploader",
+                    "title": "first",
+                    "original_url": "https://example.com/fakeuser/first",
+                    "playlist_index": 1
+                },
+                {
+                    "_type": "playlist",
+                    "id": "set",
+                    "title": "liked set",
+                    "playlist_index": 2,
+                    "entries": [
+                        {
+                            "id": "2",
+                            "uploader": "uploader",
+                            "title": "second",
+                            "original_url": "https://example.com/fakeuser/second",
+                            "playlist_index": 1
+                        },
+                        null,
+                        {
+                            "id": "3",
+                            "uploader": "uploader",
+                            "title": "third",
+                            "original_url": "https://example.com/fakeuser/third",
+                            "playlist_index": 3
+                        },
+                        {
+                            "id": "1",
+                            "uploader": "uploader",
+                            "title": "first",
+                            "original_url": "https://example.com/fakeuser/first",
+                            "playlist_index": 4
+                        }
+                    ]
+                }
+            ]
+        }"#;
+
+        let playlist = Playlist::from_manifest(input).unwrap();
+
+        let ids = playlist
+            .entries
+            .iter()
+            .map(|t| (t.id.as_str(), t.idx))
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![("1", 1), ("2", 2), ("3", 3)]);
+    }
+
+    #[test]
+    fn test_flat_playlist_manifest() {
+        let input = r#"{
+            "_type": "playlist",
+            "id": "playlist",
+            "title": "title",
+            "original_url": "https://example.com/fakeuser/sets/playlist",
+            "playlist_count": 3,
+            "entries": [
+                {
+                    "_type": "url",
+                    "ie_key": "Soundcloud",
+                    "id": "1",
+                    "url": "https://example.com/fakeuser/first"
+                },
+                null,
+                {
+                    "_type": "url",
+                    "url": "https://example.com/fakeuser/sets/nested"
+                }
+            ]
+        }"#;
+
+        let playlist = FlatPlaylist::from_manifest(input).unwrap().unwrap();
+
+        assert_eq!(playlist.entries.len(), 2);
+        assert!(!playlist.entries[0].is_playlist());
+        assert!(playlist.entries[1].is_playlist());
+
+        let single = r#"{ "_type": "video", "id": "1" }"#;
+        assert!(FlatPlaylist::from_manifest(single).unwrap().is_none());
+    }
+
     #[test]
     fn test_track_handles() {
         AppConfig::initialize();
@@ -154,6 +710,12 @@ mod tests {
             title: "title".to_string(),
             url: "https://example.com/fakeuser/track-slug".to_string(),
             idx: 0,
+            file_ext: None,
+            master_ext: None,
+            video_ext: None,
+            sponsor_segments: None,
+            version: 1,
+            metadata: Default::default(),
         };
 
         let handle = track.as_handle();
@@ -166,5 +728,25 @@ mod tests {
             handle.playlist_entry_path(),
             PathBuf::from("1234567890/track.mp3")
         );
+
+        let track = Track {
+            file_ext: Some("opus".to_string()),
+            ..track
+        };
+
+        assert_eq!(
+            track.as_handle().playlist_entry_path(),
+            PathBuf::from("1234567890/track.opus")
+        );
+
+        let track = Track {
+            version: 2,
+            ..track
+        };
+
+        assert_eq!(
+            track.as_handle().playlist_entry_path(),
+            PathBuf::from("1234567890/v2/track.opus")
+        );
     }
 }
@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use chrono::Utc;
+use color_eyre::eyre::{Context, Result, bail};
+
+use crate::{
+    config::AppConfig,
+    index::AppIndex,
+    m3u::write_playlist,
+    model::{DEFAULT_FILE_EXT, Track},
+    retry::retry_with,
+    source::AudioFormat,
+};
+
+/// Regenerates the playback file of every track that has an archival master,
+/// converting the master to the audio format configured for the track's
+/// source. The metadata of the old playback file is copied to the new one, and
+/// the old playback file is kept next to it under another name (see
+/// [`replace_playback_file`]).
+///
+/// This is used after changing the audio format, since tracks that were
+/// already downloaded are otherwise kept in the format they were downloaded in.
+#[instrument(skip(index))]
+pub fn regenerate_playback_files(index: &mut AppIndex) -> Result<()> {
+    let sources = &AppConfig::get().sources;
+
+    // maps track ID to the extension of its new playback file. tracks can be
+    // part of several playlists, but only need to be regenerated once
+    let mut regenerated = HashMap::new();
+
+    for (url, playlist) in &index.playlists {
+        let Some(source) = sources.iter().find(|source| &source.url == url) else {
+            warn!("source {} is not in the config anymore, skipping", url);
+            continue;
+        };
+
+        let format = source.audio_format();
+
+        for track in playlist
+            .entries
+            .iter()
+            .filter_map(|t| t.resolve(&index.tracks))
+        {
+            if track.master_ext.is_none() || regenerated.contains_key(&track.id) {
+                continue;
+            }
+
+            match regenerate_track(&track, format) {
+                Ok(Some(ext)) => {
+                    info!("regenerated {:?} ({})", track.title, track.id);
+                    regenerated.insert(track.id.clone(), ext);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    error!(
+                        "failed to regenerate {:?} ({}): {:?}",
+                        track.title, track.id, err
+                    );
+                }
+            }
+        }
+    }
+
+    info!("regenerated {} playback files", regenerated.len());
+
+    for (id, ext) in &regenerated {
+        if let Some(record) = index.tracks.get_mut(id) {
+            record.track.file_ext = Some(ext.clone());
+            record.read_file_info();
+            index.changes.tracks.insert(id.clone());
+        }
+    }
+
+    for playlist in index.playlists.values() {
+        retry_with(
+            || write_playlist(playlist, &index.tracks),
+            "failed to write playlist",
+        )?;
+    }
+
+    index.save()?;
+
+    Ok(())
+}
+
+/// Converts the master of a track into a new playback file. Returns the
+/// extension of the new playback file, or `None` if the track is already saved
+/// in the given format (or the original format, in which case there is nothing
+/// to convert).
+fn regenerate_track(track: &Track, format: AudioFormat) -> Result<Option<String>> {
+    let (ext, codec_args): (&str, &[&str]) = match format {
+        AudioFormat::Mp3 => ("mp3", &["-c:a", "libmp3lame", "-q:a", "0"]),
+        AudioFormat::Opus => ("opus", &["-c:a", "libopus", "-b:a", "192k"]),
+        AudioFormat::M4a => ("m4a", &["-c:a", "aac", "-q:a", "2"]),
+        AudioFormat::Flac => ("flac", &["-c:a", "flac"]),
+        AudioFormat::Original => {
+            debug!("track is saved in the original format, skipping");
+            return Ok(None);
+        }
+    };
+
+    if track.file_ext.as_deref().unwrap_or(DEFAULT_FILE_EXT) == ext {
+        debug!("track is already saved in the configured format, skipping");
+        return Ok(None);
+    }
+
+    let handle = track.as_handle();
+
+    let Some(master_path) = handle.master_path.as_ref().filter(|path| path.exists()) else {
+        bail!("master does not exist");
+    };
+
+    // ffmpeg picks the output format using the extension, so it has to stay
+    // at the end
+    let temp_path = handle.root_dir.join(format!("track.regenerating.{ext}"));
+
+    let mut cmd = Command::new(&AppConfig::get().ffmpeg_path);
+
+    cmd.args(["-y", "-loglevel", "error", "-i"])
+        .arg(master_path);
+
+    if handle.track_path.exists() {
+        cmd.arg("-i")
+            .arg(&handle.track_path)
+            .args(["-map_metadata", "1"]);
+    }
+
+    cmd.args(["-map", "0:a"]);
+    cmd.args(codec_args);
+    cmd.arg(&temp_path);
+
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    let output = cmd.output().wrap_err("failed to run ffmpeg")?;
+
+    if !output.status.success() {
+        let _ = fs::remove_file(&temp_path);
+        bail!("{}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    replace_playback_file(&temp_path, &handle.track_path, ext)?;
+
+    Ok(Some(ext.to_string()))
+}
+
+/// Moves a regenerated playback file into place. The old playback file (and a
+/// file left over from an interrupted regeneration to the same format) is moved
+/// aside to `track.superseded-<time>.<ext>` instead of being removed, since we
+/// never delete audio. The track's playback file is always `track.<ext>`, so
+/// the files that were moved aside are never mistaken for it.
+fn replace_playback_file(temp_path: &Path, old_path: &Path, ext: &str) -> Result<()> {
+    let new_path = old_path.with_extension(ext);
+    let time = Utc::now().format("%Y%m%dT%H%M%S%.6fZ");
+
+    let move_aside = |path: &Path| -> Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        let aside = path.with_extension(format!("superseded-{time}.{ext}"));
+
+        if aside.exists() {
+            bail!("{} already exists", aside.display());
+        }
+
+        fs::rename(path, &aside).wrap_err("failed to move old playback file aside")
+    };
+
+    move_aside(&new_path)?;
+
+    // renaming is atomic, so the track always has a playback file
+    fs::rename(temp_path, &new_path).wrap_err("failed to move playback file")?;
+
+    if old_path != new_path {
+        move_aside(old_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_playback_file() {
+        AppConfig::initialize();
+
+        let dir = AppConfig::get().paths.audio.join("replace-playback-file");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let regenerate = |from: &str, to: &str| {
+            let temp_path = dir.join(format!("track.regenerating.{to}"));
+            fs::write(&temp_path, to).unwrap();
+            replace_playback_file(&temp_path, &dir.join(format!("track.{from}")), to).unwrap();
+        };
+
+        // the names of the playback files, and the contents of the files
+        // that were moved aside
+        let files = || {
+            let mut playback = Vec::new();
+            let mut superseded = Vec::new();
+
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_str().unwrap().to_string();
+
+                if name.starts_with("track.superseded-") {
+                    superseded.push(fs::read_to_string(&path).unwrap());
+                } else {
+                    playback.push(name);
+                }
+            }
+
+            playback.sort();
+            superseded.sort();
+            (playback, superseded)
+        };
+
+        fs::write(dir.join("track.mp3"), "mp3").unwrap();
+
+        regenerate("mp3", "opus");
+        assert_eq!(files(), (vec!["track.opus".into()], vec!["mp3".into()]));
+
+        // a round trip ends up where it started, and nothing is removed
+        regenerate("opus", "mp3");
+        assert_eq!(
+            files(),
+            (vec!["track.mp3".into()], vec!["mp3".into(), "opus".into()])
+        );
+        assert_eq!(fs::read_to_string(dir.join("track.mp3")).unwrap(), "mp3");
+
+        // a file left over from an interrupted regeneration is moved aside as
+        // well
+        fs::write(dir.join("track.flac"), "stale").unwrap();
+        regenerate("mp3", "flac");
+        assert_eq!(files().0, ["track.flac"]);
+        assert_eq!(files().1, ["mp3", "mp3", "opus", "stale"]);
+        assert_eq!(fs::read_to_string(dir.join("track.flac")).unwrap(), "flac");
+    }
+}
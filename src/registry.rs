@@ -0,0 +1,124 @@
+use std::{collections::BTreeSet, fs};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    model::{SingleTrack, Track},
+    source::{Platform, failure::FailureReason},
+};
+
+/// Everything the index knows about a track, shared between every source the
+/// track is part of. The index keeps one record per track ID, and the stored
+/// playlists only refer to them.
+///
+/// The files of the track are described by the extensions and version in its
+/// manifest (see [`SingleTrack::as_handle`]).
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackRecord {
+    /// The latest manifest of the track
+    pub track: SingleTrack,
+    /// The platform of the source the track was first seen in
+    pub platform: Option<Platform>,
+    /// When the track was first seen in a source. This is `None` for tracks
+    /// indexed before the registry existed
+    pub first_seen: Option<DateTime<Utc>>,
+    /// When the track was last seen in a source
+    pub last_seen: Option<DateTime<Utc>>,
+    /// When the latest version of the track was downloaded, which is the
+    /// time its playback file was last written
+    pub downloaded_at: Option<DateTime<Utc>>,
+    /// The size of the playback file in bytes
+    pub file_size: Option<u64>,
+    /// The URLs of the sources the track is currently part of
+    #[serde(default)]
+    pub sources: BTreeSet<String>,
+    #[serde(default)]
+    pub availability: Availability,
+}
+
+/// Whether a track can still be fetched from its platform.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", content = "reason", rename_all = "snake_case")]
+pub enum Availability {
+    #[default]
+    Available,
+    Deleted(Option<FailureReason>),
+    Restricted(Option<FailureReason>),
+}
+
+impl Availability {
+    /// The name of the status, e.g. `deleted`.
+    pub fn status(&self) -> &'static str {
+        match self {
+            Self::Available => "available",
+            Self::Deleted(_) => "deleted",
+            Self::Restricted(_) => "restricted",
+        }
+    }
+
+    pub fn reason(&self) -> Option<FailureReason> {
+        match self {
+            Self::Available => None,
+            Self::Deleted(reason) | Self::Restricted(reason) => *reason,
+        }
+    }
+}
+
+impl TrackRecord {
+    pub fn new(track: &Track) -> Self {
+        Self {
+            track: track.clone().with_idx(()),
+            platform: None,
+            first_seen: None,
+            last_seen: None,
+            downloaded_at: None,
+            file_size: None,
+            sources: BTreeSet::new(),
+            availability: Availability::Available,
+        }
+    }
+
+    /// Returns the track as an entry of a playlist.
+    pub fn entry(&self, idx: usize) -> Track {
+        self.track.clone().with_idx(idx)
+    }
+
+    /// Records that the track is part of a source, using its manifest from
+    /// that source. `now` is `None` if it isn't known when the track was seen.
+    pub fn seen_in(
+        &mut self,
+        track: &Track,
+        source_url: &str,
+        platform: Option<Platform>,
+        now: Option<DateTime<Utc>>,
+    ) {
+        self.track = track.clone().with_idx(());
+        self.platform = self.platform.or(platform);
+        self.first_seen = self.first_seen.or(now);
+        self.last_seen = now.or(self.last_seen);
+        self.sources.insert(source_url.to_string());
+        self.availability = Availability::Available;
+    }
+
+    /// Records that the track is no longer part of a source, because it was
+    /// removed from it or became unavailable.
+    pub fn left(&mut self, source_url: &str, availability: Option<Availability>) {
+        self.sources.remove(source_url);
+
+        if let Some(availability) = availability {
+            self.availability = availability;
+        }
+    }
+
+    /// Reads the size and modification time of the playback file, which is
+    /// done whenever a new version of the track is downloaded.
+    pub fn read_file_info(&mut self) {
+        let Ok(metadata) = fs::metadata(self.track.as_handle().track_path) else {
+            return;
+        };
+
+        self.file_size = Some(metadata.len());
+        self.downloaded_at = metadata.modified().ok().map(DateTime::from);
+    }
+}
@@ -0,0 +1,172 @@
+use std::process::Output;
+
+use color_eyre::eyre::Result;
+
+use crate::model::{Playlist, Track};
+
+use super::{
+    Fetcher, SourceDefinition, TrackDownloadStatus, TrackStatus, failure::FailureReason,
+    source_bail,
+};
+
+/// Archives Bandcamp tracks, albums and discographies. Discographies are
+/// flattened into the tracks of each album, in album order.
+pub struct Bandcamp;
+
+/// Errors produced by tracks that cannot be streamed, which usually means they
+/// are only available to people who purchased the album.
+const UNAVAILABLE_ERRS: &[&str] = &[
+    "not available for streaming",
+    "This track is not available",
+    "No video formats found",
+];
+
+fn is_unavailable(line: &str) -> bool {
+    UNAVAILABLE_ERRS.iter().any(|err| line.contains(err))
+}
+
+fn check_playlist_errors(output: &Output) -> Result<()> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let lines = stderr.lines().collect::<Vec<_>>();
+
+    // albums frequently contain purchase-only tracks, which will cause yt-dlp
+    // to exit with an error even though the rest of the album was fetched
+    if !lines.iter().all(|line| is_unavailable(line)) {
+        source_bail!(stderr);
+    }
+
+    warn!(
+        "{} songs were not available for streaming (likely purchase-only)! they will be ignored.",
+        lines.len()
+    );
+
+    Ok(())
+}
+
+fn check_track_errors(output: &Output) -> Result<TrackStatus> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let lines = stderr.lines().collect::<Vec<_>>();
+
+    if !lines.is_empty() && lines.iter().all(|line| is_unavailable(line)) {
+        return Ok(TrackStatus::Unavailable(FailureReason::Restricted));
+    }
+
+    super::check_track_errors_common(output)
+}
+
+impl Fetcher for Bandcamp {
+    fn fetch_playlist(
+        &self,
+        source: &SourceDefinition,
+        previous: Option<&Playlist>,
+    ) -> Result<Playlist> {
+        super::fetch_playlist_generic(
+            source,
+            &source.url,
+            previous,
+            check_playlist_errors,
+            |source, url| super::fetch_track_generic(source, url, check_track_errors),
+        )
+    }
+
+    fn fetch_track(&self, source: &SourceDefinition, track: &Track) -> Result<TrackStatus> {
+        super::fetch_track_generic(source, &track.url, check_track_errors)
+    }
+
+    fn ensure_track_downloaded(
+        &self,
+        source: &SourceDefinition,
+        track: &Track,
+    ) -> Result<TrackDownloadStatus> {
+        super::ensure_track_downloaded_generic(source, track)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+    use crate::model::Playlist;
+
+    fn output(stderr: &str) -> Output {
+        Output {
+            status: std::process::ExitStatus::from_raw(1 << 8),
+            stdout: Vec::new(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_check_errors() {
+        // purchase-only tracks don't fail the whole album
+        let purchase_only = output(
+            "ERROR: [Bandcamp] 123: This track is not available for streaming\n\
+             ERROR: [Bandcamp] 456: This track is not available for streaming\n",
+        );
+        assert!(check_playlist_errors(&purchase_only).is_ok());
+        assert!(matches!(
+            check_track_errors(&purchase_only).unwrap(),
+            TrackStatus::Unavailable(FailureReason::Restricted)
+        ));
+
+        let not_found = output("ERROR: [Bandcamp] 123: HTTP Error 404: Not Found\n");
+        assert!(check_playlist_errors(&not_found).is_err());
+        assert!(matches!(
+            check_track_errors(&not_found).unwrap(),
+            TrackStatus::Unavailable(FailureReason::NotFound)
+        ));
+
+        assert!(check_track_errors(&output("ERROR: something else\n")).is_err());
+    }
+
+    #[test]
+    fn test_discography_manifest() {
+        // a discography is a playlist of albums, which are flattened into
+        // their tracks in album order
+        let album = |id: &str, tracks: &[(&str, usize)]| {
+            let entries = tracks
+                .iter()
+                .map(|(track, idx)| {
+                    format!(
+                        r#"{{ "id": "{track}", "uploader": "artist", "title": "{track}",
+                            "original_url": "https://artist.bandcamp.com/track/{track}",
+                            "playlist_index": {idx} }}"#
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+
+            format!(
+                r#"{{ "_type": "playlist", "id": "{id}", "title": "{id}",
+                    "entries": [{entries}] }}"#
+            )
+        };
+
+        let input = format!(
+            r#"{{
+                "_type": "playlist",
+                "id": "artist",
+                "title": "artist",
+                "original_url": "https://artist.bandcamp.com/music",
+                "playlist_count": 2,
+                "entries": [{}, {}]
+            }}"#,
+            album("first", &[("1", 1), ("2", 2)]),
+            album("second", &[("3", 1)]),
+        );
+
+        let playlist = Playlist::from_manifest(&input).unwrap();
+
+        assert_eq!(
+            playlist
+                .entries
+                .iter()
+                .map(|t| (t.id.as_str(), t.idx))
+                .collect::<Vec<_>>(),
+            [("1", 1), ("2", 2), ("3", 3)]
+        );
+    }
+}
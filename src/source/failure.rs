@@ -0,0 +1,211 @@
+use std::fmt;
+
+use color_eyre::eyre::Report;
+
+/// Why a track could not be fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// The track is not available in our country
+    GeoBlocked,
+    /// The track was made private
+    Private,
+    /// The track requires confirming our age
+    AgeGated,
+    /// The track requires an account (or a subscription) to access
+    LoginRequired,
+    /// The track is restricted for a reason we could not classify
+    Restricted,
+
+    /// The track was deleted by the uploader
+    RemovedByUploader,
+    /// The track was taken down because of a copyright claim
+    CopyrightTakedown,
+    /// The track does not exist anymore for a reason we could not classify
+    NotFound,
+
+    /// The cookies used to authenticate with the platform are no longer valid
+    CookiesExpired,
+    /// The platform is rate limiting us
+    RateLimited,
+    /// yt-dlp could not understand the platform's response, which usually
+    /// means yt-dlp needs to be updated
+    ExtractorBroken,
+}
+
+/// How a [`FailureReason`] affects the state of a track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The track still exists but we can't access it
+    Restricted,
+    /// The track does not exist anymore
+    Deleted,
+    /// The failure says nothing about the track itself and the fetch should
+    /// be tried again later
+    Transient,
+}
+
+impl FailureReason {
+    pub fn kind(&self) -> FailureKind {
+        use FailureReason::*;
+
+        match self {
+            GeoBlocked | Private | AgeGated | LoginRequired | Restricted => FailureKind::Restricted,
+            RemovedByUploader | CopyrightTakedown | NotFound => FailureKind::Deleted,
+            CookiesExpired | RateLimited | ExtractorBroken => FailureKind::Transient,
+        }
+    }
+
+    /// Classifies an error returned by a yt-dlp invocation, which contains the
+    /// error output of yt-dlp.
+    pub fn classify_error(err: &Report) -> Option<Self> {
+        Self::classify(&format!("{err:?}"))
+    }
+
+    /// Classifies yt-dlp's error output using error messages that are shared
+    /// between platforms. Returns `None` if none of the known messages were
+    /// found.
+    pub fn classify(stderr: &str) -> Option<Self> {
+        let stderr = stderr.to_lowercase();
+
+        PATTERNS
+            .iter()
+            .find(|(patterns, _)| patterns.iter().any(|pattern| stderr.contains(pattern)))
+            .map(|(_, reason)| *reason)
+    }
+}
+
+/// Known (lowercase) error messages, in the order they are checked. The order
+/// matters because some messages contain others (e.g. YouTube's bot check asks
+/// us to sign in).
+const PATTERNS: &[(&[&str], FailureReason)] = &[
+    (
+        &["cookies are no longer valid"],
+        FailureReason::CookiesExpired,
+    ),
+    (
+        &[
+            "http error 429",
+            "too many requests",
+            "confirm you're not a bot",
+            "rate-limited",
+        ],
+        FailureReason::RateLimited,
+    ),
+    (
+        &[
+            "confirm your age",
+            "age-restricted",
+            "inappropriate for some users",
+        ],
+        FailureReason::AgeGated,
+    ),
+    (
+        &[
+            "geo restriction",
+            "not available from your location",
+            "not available in your country",
+        ],
+        FailureReason::GeoBlocked,
+    ),
+    (
+        &["private video", "this track is private"],
+        FailureReason::Private,
+    ),
+    (&["copyright"], FailureReason::CopyrightTakedown),
+    (
+        &["removed by the uploader", "has been terminated"],
+        FailureReason::RemovedByUploader,
+    ),
+    (
+        &[
+            "members-only",
+            "only available for registered users",
+            "only available to music premium",
+            "sign in",
+            "login required",
+            "requires authentication",
+        ],
+        FailureReason::LoginRequired,
+    ),
+    (
+        &["unable to extract", "please report this issue"],
+        FailureReason::ExtractorBroken,
+    ),
+    (&["http error 404"], FailureReason::NotFound),
+    (&["video unavailable"], FailureReason::Restricted),
+];
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::GeoBlocked => "geo-restricted",
+            Self::Private => "private",
+            Self::AgeGated => "age-restricted",
+            Self::LoginRequired => "login required",
+            Self::Restricted => "restricted for an unknown reason",
+            Self::RemovedByUploader => "removed by the uploader",
+            Self::CopyrightTakedown => "taken down due to a copyright claim",
+            Self::NotFound => "not found",
+            Self::CookiesExpired => "cookies expired",
+            Self::RateLimited => "rate limited",
+            Self::ExtractorBroken => "yt-dlp extractor broken",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let cases = [
+            (
+                "ERROR: [youtube] abc: Sign in to confirm you're not a bot. Use --cookies-from-browser or --cookies for the authentication.",
+                Some(FailureReason::RateLimited),
+            ),
+            (
+                "ERROR: [youtube] abc: Sign in to confirm your age. This video may be inappropriate for some users.",
+                Some(FailureReason::AgeGated),
+            ),
+            (
+                "ERROR: [youtube] abc: Video unavailable. This video is no longer available due to a copyright claim by Label",
+                Some(FailureReason::CopyrightTakedown),
+            ),
+            (
+                "ERROR: [youtube] abc: Private video. Sign in if you've been granted access to this video",
+                Some(FailureReason::Private),
+            ),
+            (
+                "ERROR: [soundcloud] 123: This video is not available from your location due to geo restriction\nYou might want to use a VPN or a proxy server (with --proxy) to workaround.",
+                Some(FailureReason::GeoBlocked),
+            ),
+            (
+                "ERROR: [soundcloud] 123: Unable to download JSON metadata: HTTP Error 404: Not Found",
+                Some(FailureReason::NotFound),
+            ),
+            (
+                "ERROR: [youtube] abc: Video unavailable",
+                Some(FailureReason::Restricted),
+            ),
+            (
+                "WARNING: [youtube] The provided YouTube account cookies are no longer valid. They have likely been rotated in the browser as a security measure.\nERROR: [youtube] abc: Private video. Sign in if you've been granted access to this video",
+                Some(FailureReason::CookiesExpired),
+            ),
+            (
+                "ERROR: [youtube] abc: Join this channel to get access to members-only content like this video, and other exclusive perks.",
+                Some(FailureReason::LoginRequired),
+            ),
+            ("ERROR: something else entirely", None),
+        ];
+
+        for (stderr, expected) in cases {
+            assert_eq!(FailureReason::classify(stderr), expected, "{stderr}");
+        }
+
+        // tracks we can't access with our account are restricted, not a sign
+        // that our cookies expired
+        assert_eq!(FailureReason::LoginRequired.kind(), FailureKind::Restricted);
+    }
+}
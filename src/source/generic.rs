@@ -0,0 +1,164 @@
+use std::process::Output;
+
+use color_eyre::eyre::Result;
+use regex::Regex;
+use serde_with::{DisplayFromStr, serde_as};
+
+use crate::{
+    config::AppConfig,
+    model::{Playlist, Track},
+};
+
+use super::{
+    Fetcher, SourceDefinition, TrackDownloadStatus, TrackStatus, failure::FailureReason,
+    source_bail,
+};
+
+/// Archives any URL yt-dlp supports. Errors reported by yt-dlp are classified
+/// using the error rules from the config instead of platform-specific code.
+pub struct Generic;
+
+/// Maps a line of yt-dlp's error output to a failure reason.
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct ErrorRule {
+    #[serde_as(as = "DisplayFromStr")]
+    pub pattern: Regex,
+    pub status: FailureReason,
+}
+
+/// The rules that apply to a source: its own rules, followed by the global
+/// rules.
+fn rules(source: &SourceDefinition) -> impl Iterator<Item = &ErrorRule> {
+    source
+        .error_rules
+        .iter()
+        .chain(&AppConfig::get().error_rules)
+}
+
+/// Returns the lines of stderr that are actual errors. Warnings are printed to
+/// stderr as well, but they don't say anything about the status of a track.
+fn error_lines(stderr: &str) -> impl Iterator<Item = &str> {
+    stderr
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with("WARNING:"))
+}
+
+/// Finds the status of the first rule that matches any of the error lines.
+fn classify<'a>(
+    mut rules: impl Iterator<Item = &'a ErrorRule>,
+    stderr: &str,
+) -> Option<FailureReason> {
+    rules
+        .find(|rule| error_lines(stderr).any(|line| rule.pattern.is_match(line)))
+        .map(|rule| rule.status)
+}
+
+fn check_playlist_errors(source: &SourceDefinition, output: &Output) -> Result<()> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let mut ignored = 0;
+
+    for line in error_lines(&stderr) {
+        if !rules(source).any(|rule| rule.pattern.is_match(line)) {
+            source_bail!(stderr);
+        }
+
+        ignored += 1;
+    }
+
+    warn!(
+        "{} songs were not available according to the error rules! they will be ignored.",
+        ignored
+    );
+
+    Ok(())
+}
+
+fn check_track_errors(source: &SourceDefinition, output: &Output) -> Result<TrackStatus> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    match classify(rules(source), &stderr) {
+        Some(reason) => Ok(TrackStatus::Unavailable(reason)),
+        None => source_bail!(stderr),
+    }
+}
+
+impl Fetcher for Generic {
+    fn fetch_playlist(
+        &self,
+        source: &SourceDefinition,
+        previous: Option<&Playlist>,
+    ) -> Result<Playlist> {
+        super::fetch_playlist_generic(
+            source,
+            &source.url,
+            previous,
+            |output| check_playlist_errors(source, output),
+            |source, url| {
+                super::fetch_track_generic(source, url, |output| check_track_errors(source, output))
+            },
+        )
+    }
+
+    fn fetch_track(&self, source: &SourceDefinition, track: &Track) -> Result<TrackStatus> {
+        super::fetch_track_generic(source, &track.url, |output| {
+            check_track_errors(source, output)
+        })
+    }
+
+    fn ensure_track_downloaded(
+        &self,
+        source: &SourceDefinition,
+        track: &Track,
+    ) -> Result<TrackDownloadStatus> {
+        super::ensure_track_downloaded_generic(source, track)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, status: FailureReason) -> ErrorRule {
+        ErrorRule {
+            pattern: Regex::new(pattern).unwrap(),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let rules = [
+            rule("(?i)geo.?restrict", FailureReason::GeoBlocked),
+            rule("HTTP Error 404", FailureReason::NotFound),
+        ];
+
+        let stderr = "WARNING: [generic] HTTP Error 404 while fetching thumbnail\n\
+                      ERROR: [generic] 1234: This track is geo-restricted\n";
+        assert_eq!(
+            classify(rules.iter(), stderr),
+            Some(FailureReason::GeoBlocked)
+        );
+
+        let stderr = "ERROR: [generic] 1234: Unable to download webpage: HTTP Error 404\n";
+        assert_eq!(
+            classify(rules.iter(), stderr),
+            Some(FailureReason::NotFound)
+        );
+
+        // warnings are never classified
+        let stderr = "WARNING: [generic] HTTP Error 404 while fetching thumbnail\n";
+        assert_eq!(classify(rules.iter(), stderr), None);
+    }
+
+    #[test]
+    fn test_deserialize_rule() {
+        let input = r#"{ "pattern": "Video unavailable", "status": "not_found" }"#;
+
+        let rule: ErrorRule = serde_json::from_str(input).unwrap();
+
+        assert!(rule.pattern.is_match("ERROR: Video unavailable"));
+        assert_eq!(rule.status, FailureReason::NotFound);
+    }
+}
@@ -1,41 +1,210 @@
 use std::{
+    collections::HashMap,
     fs,
+    path::PathBuf,
     process::{Command, Output, Stdio},
 };
 
-use color_eyre::eyre::{Context, Result, eyre};
+use color_eyre::eyre::{Context, Result, bail, eyre};
 
-use crate::model::{Playlist, SingleTrack, Track, TrackHandle};
+use crate::{
+    config::AppConfig,
+    index::AppIndex,
+    lyrics::LyricsOptions,
+    model::{AUDIO_EXTENSIONS, FlatEntry, FlatPlaylist, Playlist, SingleTrack, Track, TrackHandle},
+    sponsorblock::{self, SponsorBlockOptions},
+    util::{self, Redacted},
+};
+
+use self::failure::FailureReason;
 
+pub mod bandcamp;
+pub mod failure;
+pub mod generic;
+pub mod proxy;
+#[cfg(test)]
+pub mod scripted;
 pub mod soundcloud;
 pub mod youtube;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum SourceType {
+    SoundCloud,
+    /// Every track uploaded by a SoundCloud user
+    #[serde(rename = "soundcloud-tracks")]
+    SoundCloudTracks,
+    /// Every track liked by a SoundCloud user
+    #[serde(rename = "soundcloud-likes")]
+    SoundCloudLikes,
+    /// Every track reposted by a SoundCloud user
+    #[serde(rename = "soundcloud-reposts")]
+    SoundCloudReposts,
+    YouTube,
+    /// Selected tabs of a YouTube channel
+    #[serde(rename = "youtube-channel")]
+    YouTubeChannel,
+    /// A Bandcamp track, album or discography
+    Bandcamp,
+    /// Any URL yt-dlp supports, with errors classified by the configured
+    /// error rules
+    Generic,
+}
+
+/// The format tracks are saved in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    #[default]
+    Mp3,
+    Opus,
+    M4a,
+    Flac,
+    /// The best audio stream the platform offers, extracted without
+    /// re-encoding. The extension depends on the codec of the stream.
+    Original,
+}
+
+impl AudioFormat {
+    /// The extension of files in this format. This is `None` for
+    /// [`AudioFormat::Original`], since it depends on the stream.
+    pub fn ext(&self) -> Option<&'static str> {
+        match self {
+            Self::Original => None,
+            _ => Some(self.ytdlp_format()),
+        }
+    }
+
+    /// The value of yt-dlp's `--audio-format` option for this format.
+    fn ytdlp_format(&self) -> &'static str {
+        match self {
+            Self::Mp3 => "mp3",
+            Self::Opus => "opus",
+            Self::M4a => "m4a",
+            Self::Flac => "flac",
+            Self::Original => "best",
+        }
+    }
+}
+
+/// The platform a source is hosted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
     SoundCloud,
     YouTube,
+    Bandcamp,
+    Generic,
+}
+
+impl SourceType {
+    pub fn platform(&self) -> Platform {
+        match self {
+            Self::SoundCloud
+            | Self::SoundCloudTracks
+            | Self::SoundCloudLikes
+            | Self::SoundCloudReposts => Platform::SoundCloud,
+            Self::YouTube | Self::YouTubeChannel => Platform::YouTube,
+            Self::Bandcamp => Platform::Bandcamp,
+            Self::Generic => Platform::Generic,
+        }
+    }
+
+    /// Normalizes a source URL for this source type. This is applied when the
+    /// config is loaded, so the normalized URL is what the index is keyed by.
+    pub fn normalize_url(&self, url: &str) -> String {
+        if let Some(feed) = self.profile_feed() {
+            return feed.feed_url(url);
+        }
+
+        match self {
+            Self::YouTubeChannel => youtube::ChannelTab::channel_url(url),
+            _ => url.to_string(),
+        }
+    }
+
+    /// Whether sources of this type use flat listings by default (see
+    /// [`fetch_playlist_flat`]). This is disabled for feeds that are mostly
+    /// made of nested playlists, and for platforms where the IDs in flat
+    /// listings may not match the IDs in full manifests.
+    pub fn flat_playlist_by_default(&self) -> bool {
+        match self {
+            Self::SoundCloud | Self::SoundCloudTracks | Self::YouTube | Self::YouTubeChannel => {
+                true
+            }
+            Self::SoundCloudLikes | Self::SoundCloudReposts | Self::Bandcamp | Self::Generic => {
+                false
+            }
+        }
+    }
+
+    fn profile_feed(&self) -> Option<soundcloud::ProfileFeed> {
+        use soundcloud::ProfileFeed;
+
+        match self {
+            Self::SoundCloudTracks => Some(ProfileFeed::Tracks),
+            Self::SoundCloudLikes => Some(ProfileFeed::Likes),
+            Self::SoundCloudReposts => Some(ProfileFeed::Reposts),
+            _ => None,
+        }
+    }
 }
 
 impl Fetcher for SourceType {
-    fn fetch_playlist(&self, source: &SourceDefinition) -> Result<Playlist> {
+    fn fetch_playlist(
+        &self,
+        source: &SourceDefinition,
+        previous: Option<&Playlist>,
+    ) -> Result<Playlist> {
+        use soundcloud::{ProfileFeed, SoundCloudProfile};
+
         match self {
-            Self::SoundCloud => soundcloud::SoundCloud.fetch_playlist(source),
-            Self::YouTube => youtube::YouTube.fetch_playlist(source),
+            Self::SoundCloud => soundcloud::SoundCloud.fetch_playlist(source, previous),
+            Self::SoundCloudTracks => {
+                SoundCloudProfile(ProfileFeed::Tracks).fetch_playlist(source, previous)
+            }
+            Self::SoundCloudLikes => {
+                SoundCloudProfile(ProfileFeed::Likes).fetch_playlist(source, previous)
+            }
+            Self::SoundCloudReposts => {
+                SoundCloudProfile(ProfileFeed::Reposts).fetch_playlist(source, previous)
+            }
+            Self::YouTube => youtube::YouTube.fetch_playlist(source, previous),
+            Self::YouTubeChannel => youtube::YouTubeChannel.fetch_playlist(source, previous),
+            Self::Bandcamp => bandcamp::Bandcamp.fetch_playlist(source, previous),
+            Self::Generic => generic::Generic.fetch_playlist(source, previous),
         }
     }
 
-    fn fetch_track(&self, track: &Track) -> Result<TrackStatus> {
+    fn fetch_track(&self, source: &SourceDefinition, track: &Track) -> Result<TrackStatus> {
         match self {
-            Self::SoundCloud => soundcloud::SoundCloud.fetch_track(track),
-            Self::YouTube => youtube::YouTube.fetch_track(track),
+            Self::SoundCloud
+            | Self::SoundCloudTracks
+            | Self::SoundCloudLikes
+            | Self::SoundCloudReposts => soundcloud::SoundCloud.fetch_track(source, track),
+            Self::YouTube => youtube::YouTube.fetch_track(source, track),
+            Self::YouTubeChannel => youtube::YouTubeChannel.fetch_track(source, track),
+            Self::Bandcamp => bandcamp::Bandcamp.fetch_track(source, track),
+            Self::Generic => generic::Generic.fetch_track(source, track),
         }
     }
 
-    fn ensure_track_downloaded(&self, track: &Track) -> Result<TrackDownloadStatus> {
+    fn ensure_track_downloaded(
+        &self,
+        source: &SourceDefinition,
+        track: &Track,
+    ) -> Result<TrackDownloadStatus> {
         match self {
-            Self::SoundCloud => soundcloud::SoundCloud.ensure_track_downloaded(track),
-            Self::YouTube => youtube::YouTube.ensure_track_downloaded(track),
+            Self::SoundCloud
+            | Self::SoundCloudTracks
+            | Self::SoundCloudLikes
+            | Self::SoundCloudReposts => {
+                soundcloud::SoundCloud.ensure_track_downloaded(source, track)
+            }
+            Self::YouTube => youtube::YouTube.ensure_track_downloaded(source, track),
+            Self::YouTubeChannel => youtube::YouTubeChannel.ensure_track_downloaded(source, track),
+            Self::Bandcamp => bandcamp::Bandcamp.ensure_track_downloaded(source, track),
+            Self::Generic => generic::Generic.ensure_track_downloaded(source, track),
         }
     }
 }
@@ -47,13 +216,160 @@ pub struct SourceDefinition {
     pub url: String,
     #[serde(default)]
     pub inactive: bool,
+    /// Extra arguments passed to every yt-dlp invocation for this source. These
+    /// come after the global `extra_args`, so they can override them.
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+    /// Options for `youtube-channel` sources.
+    #[serde(default)]
+    pub channel: youtube::ChannelOptions,
+    /// Error rules for `generic` sources. These are checked before the global
+    /// `error_rules`.
+    #[serde(default)]
+    pub error_rules: Vec<generic::ErrorRule>,
+    /// The path to a Netscape-formatted cookies file used to authenticate
+    /// every yt-dlp invocation for this source. Relative paths are resolved
+    /// against the data folder.
+    pub cookies: Option<Redacted<PathBuf>>,
+    /// The name of a credential from the config to authenticate with. Takes
+    /// precedence over `cookies`.
+    pub credential: Option<String>,
+    /// The names of the proxies (from the config) that geo-restricted tracks
+    /// are retried through, in order. If not given, all proxies are used.
+    pub proxies: Option<Vec<String>>,
+    /// Whether to fetch the playlist using a flat listing, which only fetches
+    /// the full metadata of new tracks. Defaults to a per-platform value.
+    pub flat_playlist: Option<bool>,
+    /// The maximum number of tracks from this source that are downloaded at
+    /// the same time. The global and per-platform limits still apply.
+    pub download_workers: Option<usize>,
+    /// The format tracks from this source are saved in. Overrides the global
+    /// `audio_format`.
+    pub audio_format: Option<AudioFormat>,
+    /// Whether to keep the original stream of tracks from this source as an
+    /// archival master. Overrides the global `keep_masters`.
+    pub keep_master: Option<bool>,
+    /// Whether to also keep the video of every track from this source, merged
+    /// into `video.mkv`. Only YouTube sources have videos.
+    #[serde(default)]
+    pub archive_video: bool,
+    /// Options for saving the lyrics of tracks from this source. Lyrics are not
+    /// saved if this is not given.
+    pub lyrics: Option<LyricsOptions>,
+    /// SponsorBlock segments to cut out of (or mark in) tracks from this
+    /// source. Only YouTube sources have segments.
+    pub sponsorblock: Option<SponsorBlockOptions>,
+    /// The proxy yt-dlp is currently being run through. This is set at
+    /// runtime by [`SourceDefinition::via_proxy`].
+    #[serde(skip)]
+    active_proxy: Option<proxy::Proxy>,
+}
+
+impl SourceDefinition {
+    /// Creates a yt-dlp command using the configured binary and this source's
+    /// cookies.
+    ///
+    /// The extra arguments are not added here because they must come after the
+    /// built-in arguments (so they can override them) but before the URL. Use
+    /// [`SourceDefinition::extra_args`] for that.
+    pub fn ytdlp_command(&self) -> Command {
+        let mut cmd = Command::new(&AppConfig::get().ytdlp_path);
+
+        if let Some(cookies) = self.cookies() {
+            cmd.arg("--cookies").arg(cookies);
+        }
+
+        if let Some(proxy) = &self.active_proxy {
+            cmd.arg("--proxy").arg(&*proxy.url);
+        }
+
+        cmd
+    }
+
+    /// The number of tracks from this source that can be downloaded at the
+    /// same time, which is the lowest of the global, per-platform and
+    /// per-source limits. Sources are refreshed one at a time, so no other
+    /// downloads run alongside this source's.
+    pub fn download_workers(&self) -> usize {
+        let config = AppConfig::get();
+
+        [
+            Some(config.download_workers),
+            config.platform_workers.get(&self.kind.platform()).copied(),
+            self.download_workers,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap_or(1)
+        .max(1)
+    }
+
+    /// The format tracks from this source are saved in.
+    pub fn audio_format(&self) -> AudioFormat {
+        self.audio_format.unwrap_or(AppConfig::get().audio_format)
+    }
+
+    /// Whether the original stream of tracks from this source is kept as an
+    /// archival master.
+    pub fn keep_master(&self) -> bool {
+        self.keep_master.unwrap_or(AppConfig::get().keep_masters)
+    }
+
+    /// Whether this source's playlist is fetched using a flat listing.
+    pub fn flat_playlist(&self) -> bool {
+        self.flat_playlist
+            .unwrap_or_else(|| self.kind.flat_playlist_by_default())
+    }
+
+    /// The proxies geo-restricted tracks from this source are retried
+    /// through, in order.
+    pub fn proxies(&self) -> Vec<&'static proxy::Proxy> {
+        let proxies = &AppConfig::get().proxies;
+
+        match &self.proxies {
+            // proxy names are validated when the config is loaded
+            Some(names) => names
+                .iter()
+                .filter_map(|name| proxies.iter().find(|proxy| &proxy.name == name))
+                .collect(),
+            None => proxies.iter().collect(),
+        }
+    }
+
+    /// Returns a copy of this source that runs yt-dlp through the given proxy.
+    pub fn via_proxy(&self, proxy: &proxy::Proxy) -> Self {
+        Self {
+            active_proxy: Some(proxy.clone()),
+            ..self.clone()
+        }
+    }
+
+    /// The path to the cookies file for this source, if it has one.
+    pub fn cookies(&self) -> Option<PathBuf> {
+        let config = AppConfig::get();
+
+        let path = match &self.credential {
+            // credentials are validated when the config is loaded
+            Some(name) => &config.credentials.get(name)?.cookies,
+            None => self.cookies.as_ref()?,
+        };
+
+        // joining an absolute path replaces the root, so absolute paths are
+        // left as is
+        Some(config.paths.root.join(&**path))
+    }
+
+    /// The global extra arguments followed by this source's extra arguments.
+    pub fn extra_args(&self) -> impl Iterator<Item = &String> {
+        AppConfig::get().extra_args.iter().chain(&self.extra_args)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum TrackStatus {
-    Restricted,
-    Available(SingleTrack),
-    NotFound,
+    Available(Box<SingleTrack>),
+    Unavailable(FailureReason),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -63,62 +379,366 @@ pub enum TrackDownloadStatus {
 }
 
 pub trait Fetcher {
-    fn fetch_playlist(&self, source: &SourceDefinition) -> Result<Playlist>;
+    /// Fetches the current version of a source's playlist. `previous` is the
+    /// version of the playlist from the last refresh, if there is one, which
+    /// fetchers can use to avoid fetching metadata they already have.
+    /// Geo-restricted tracks are part of the playlist if they are reachable
+    /// through one of the source's proxies.
+    fn fetch_playlist(
+        &self,
+        source: &SourceDefinition,
+        previous: Option<&Playlist>,
+    ) -> Result<Playlist>;
+
+    fn fetch_track(&self, source: &SourceDefinition, track: &Track) -> Result<TrackStatus>;
+
+    fn ensure_track_downloaded(
+        &self,
+        source: &SourceDefinition,
+        track: &Track,
+    ) -> Result<TrackDownloadStatus>;
+
+    /// The proxies geo-restricted playlists and tracks of a source are retried
+    /// through, in order.
+    fn proxies<'a>(&'a self, source: &'a SourceDefinition) -> Vec<&'a proxy::Proxy> {
+        source.proxies()
+    }
+}
+
+/// Fetches every source using the platform-specific fetcher for its
+/// [`SourceType`]. This is the fetcher used outside of tests.
+pub struct SourceFetcher;
+
+impl Fetcher for SourceFetcher {
+    fn fetch_playlist(
+        &self,
+        source: &SourceDefinition,
+        previous: Option<&Playlist>,
+    ) -> Result<Playlist> {
+        source.kind.fetch_playlist(source, previous)
+    }
 
-    fn fetch_track(&self, track: &Track) -> Result<TrackStatus>;
+    fn fetch_track(&self, source: &SourceDefinition, track: &Track) -> Result<TrackStatus> {
+        source.kind.fetch_track(source, track)
+    }
 
-    fn ensure_track_downloaded(&self, track: &Track) -> Result<TrackDownloadStatus>;
+    fn ensure_track_downloaded(
+        &self,
+        source: &SourceDefinition,
+        track: &Track,
+    ) -> Result<TrackDownloadStatus> {
+        source.kind.ensure_track_downloaded(source, track)
+    }
 }
 
-#[instrument(skip(on_error))]
-fn fetch_playlist_generic<F>(url: &str, on_error: F) -> Result<Playlist>
+/// Fetches a playlist. If the source allows it and the playlist has been
+/// fetched before, only a flat listing of the playlist is fetched and full
+/// manifests are only fetched for new tracks (see [`fetch_playlist_flat`]).
+/// Otherwise the full manifest of the playlist is fetched.
+///
+/// yt-dlp leaves geo-restricted tracks out of the full manifest, so if the
+/// source has proxies, the tracks that are only part of a flat listing of the
+/// playlist are retried through them.
+fn fetch_playlist_generic<F, T>(
+    source: &SourceDefinition,
+    url: &str,
+    previous: Option<&Playlist>,
+    on_error: F,
+    fetch_track: T,
+) -> Result<Playlist>
 where
-    F: FnOnce(&Output) -> Result<()>,
+    F: Fn(&Output) -> Result<()>,
+    T: Fn(&SourceDefinition, &str) -> Result<TrackStatus>,
+{
+    if let Some(previous) = previous.filter(|_| source.flat_playlist()) {
+        return fetch_playlist_flat(source, url, previous, on_error, fetch_track);
+    }
+
+    let playlist = fetch_playlist_full(source, url, &on_error)?;
+    let proxies = source.proxies();
+
+    if proxies.is_empty() {
+        return Ok(playlist);
+    }
+
+    trace!("fetching flat playlist listing to find geo-restricted tracks");
+
+    let stdout = fetch_manifest(source, url, &["-J", "--flat-playlist"], &on_error)?;
+
+    match FlatPlaylist::from_manifest(&stdout)? {
+        // nested playlists are expanded in the full manifest, but not in the
+        // listing
+        Some(listing) if !listing.entries.iter().any(FlatEntry::is_playlist) => Ok(
+            resolve_flat_listing(source, &proxies, listing, &playlist, fetch_track),
+        ),
+        _ => Ok(playlist),
+    }
+}
+
+/// Fetches the full manifest of a playlist, which includes the full manifest of
+/// every track. This can take a very long time for large playlists.
+#[instrument(skip(source, on_error))]
+fn fetch_playlist_full<F>(source: &SourceDefinition, url: &str, on_error: F) -> Result<Playlist>
+where
+    F: Fn(&Output) -> Result<()>,
 {
     trace!("fetching playlist manifest");
 
-    let mut cmd = Command::new("yt-dlp");
+    let stdout = fetch_manifest(source, url, &["-J"], &on_error)?;
+
+    Ok(Playlist::from_manifest(&stdout)?)
+}
+
+/// Fetches a flat listing of a playlist, then fetches the full manifest of each
+/// track that is not part of the previous version of the playlist. Tracks that
+/// were already in the playlist reuse their previous metadata, unless the
+/// listing suggests they were replaced. New tracks that are geo-restricted are
+/// retried through the source's proxies.
+///
+/// If the listing contains nested playlists, the full manifest is fetched
+/// instead, since flat listings don't expand them.
+#[instrument(skip(source, previous, on_error, fetch_track))]
+fn fetch_playlist_flat<F, T>(
+    source: &SourceDefinition,
+    url: &str,
+    previous: &Playlist,
+    on_error: F,
+    fetch_track: T,
+) -> Result<Playlist>
+where
+    F: Fn(&Output) -> Result<()>,
+    T: Fn(&SourceDefinition, &str) -> Result<TrackStatus>,
+{
+    trace!("fetching flat playlist listing");
+
+    let stdout = fetch_manifest(source, url, &["-J", "--flat-playlist"], &on_error)?;
+
+    let Some(listing) = FlatPlaylist::from_manifest(&stdout)? else {
+        // there is nothing to flatten in a single track, so this is already a
+        // full manifest
+        return Ok(Playlist::from_manifest(&stdout)?);
+    };
+
+    if listing.entries.iter().any(FlatEntry::is_playlist) {
+        debug!("listing contains nested playlists, fetching the full manifest instead");
+        return fetch_playlist_full(source, url, on_error);
+    }
+
+    Ok(resolve_flat_listing(
+        source,
+        &source.proxies(),
+        listing,
+        previous,
+        fetch_track,
+    ))
+}
+
+/// Turns a flat listing into a playlist, reusing the metadata of the tracks in
+/// `previous` (the previous version of the playlist, or its full manifest) and
+/// fetching the full manifest of the other tracks through `proxies` if they
+/// are geo-restricted. New tracks that can't be fetched are left out, like they are in the
+/// full manifest, so they are tried again on the next refresh.
+///
+/// The full manifest of a known track is fetched again if the title or
+/// duration in the listing already count as a replacement, so the replacement
+/// is detected like it would be with the full manifest.
+fn resolve_flat_listing<T>(
+    source: &SourceDefinition,
+    proxies: &[&proxy::Proxy],
+    listing: FlatPlaylist,
+    previous: &Playlist,
+    fetch_track: T,
+) -> Playlist
+where
+    T: Fn(&SourceDefinition, &str) -> Result<TrackStatus>,
+{
+    let mut entries = Vec::with_capacity(listing.entries.len());
+    let mut fetched = 0;
 
-    cmd.args(["-J", url, "-t", "sleep"]);
+    for (pos, entry) in listing.entries.into_iter().enumerate() {
+        let idx = entry.idx.unwrap_or(pos + 1);
+
+        let known = entry
+            .id
+            .as_ref()
+            .and_then(|id| previous.entries.iter().find(|track| &track.id == id));
+
+        if let Some(track) = known {
+            let mut listed = track.clone();
+            if let Some(title) = &entry.title {
+                listed.title = title.clone();
+            }
+            if let Some(duration) = entry.duration {
+                listed.metadata.duration = Some(duration);
+            }
+
+            if AppConfig::get()
+                .replacement_detection
+                .is_replaced(track, &listed)
+            {
+                debug!(
+                    "listing suggests {} was replaced, fetching it again",
+                    entry.url
+                );
+                fetched += 1;
+
+                match fetch_track(source, &entry.url) {
+                    Ok(TrackStatus::Available(track)) => {
+                        entries.push((*track).with_idx(idx));
+                        continue;
+                    }
+                    Ok(TrackStatus::Unavailable(reason)) => {
+                        warn!(
+                            "track {} is not available ({}), keeping its previous metadata",
+                            entry.url, reason
+                        );
+                    }
+                    Err(err) => {
+                        warn!(
+                            "failed to fetch track {}, keeping its previous metadata: {:?}",
+                            entry.url, err
+                        );
+                    }
+                }
+            }
+
+            entries.push(Track {
+                idx,
+                ..track.clone()
+            });
+            continue;
+        }
+
+        fetched += 1;
+
+        // which proxy worked is recorded once the track is downloaded
+        let result = proxy::with_proxy_fallback(
+            source,
+            proxies,
+            &entry.url,
+            &mut HashMap::new(),
+            |source| fetch_track(source, &entry.url),
+            |result| {
+                matches!(
+                    result,
+                    Ok(TrackStatus::Unavailable(FailureReason::GeoBlocked))
+                )
+            },
+        );
+
+        match result {
+            Ok(TrackStatus::Available(track)) => entries.push((*track).with_idx(idx)),
+            Ok(TrackStatus::Unavailable(reason)) => {
+                // the full manifest leaves out unavailable tracks as well
+                warn!(
+                    "new track {} is not available ({}), it will be ignored",
+                    entry.url, reason
+                );
+            }
+            Err(err) => {
+                warn!(
+                    "failed to fetch new track {}, it will be ignored: {:?}",
+                    entry.url, err
+                );
+            }
+        }
+    }
+
+    debug!("fetched the full manifest of {} new tracks", fetched);
+
+    Playlist {
+        id: listing.id,
+        title: listing.title,
+        entries,
+        url: listing.url,
+        len: listing.len,
+        sections: Vec::new(),
+    }
+}
+
+/// Runs a yt-dlp command to completion, killing it if a shutdown is requested
+/// (see [`AppIndex::request_shutdown`]).
+fn run_ytdlp(cmd: &mut Command) -> Result<Output> {
+    util::output_unless(cmd, AppIndex::is_shutdown_requested).wrap_err("failed to run yt-dlp")
+}
+
+/// Runs yt-dlp with the given arguments to fetch a manifest, returning its
+/// stdout.
+fn fetch_manifest<F>(
+    source: &SourceDefinition,
+    url: &str,
+    args: &[&str],
+    on_error: &F,
+) -> Result<String>
+where
+    F: Fn(&Output) -> Result<()>,
+{
+    let mut cmd = source.ytdlp_command();
+
+    cmd.args(args);
+    cmd.args(["-t", "sleep"]);
+    cmd.args(source.extra_args());
+    cmd.arg(url);
 
     cmd.stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
 
-    let output = cmd.output()?;
+    let output = run_ytdlp(&mut cmd)?;
 
     if !output.status.success()
         && let Err(err) = on_error(&output)
     {
+        match FailureReason::classify(&String::from_utf8_lossy(&output.stderr)) {
+            Some(FailureReason::CookiesExpired) => {
+                error!("the cookies for this source have expired and must be replaced");
+            }
+            // if we are authenticated and the platform still wants us to log
+            // in to see the playlist, our cookies are no longer valid
+            Some(FailureReason::LoginRequired) if source.cookies().is_some() => {
+                error!("the cookies for this source have likely expired and must be replaced");
+            }
+            _ => {}
+        }
+
+        // if yt-dlp did not output anything, there is nothing to parse so we
+        // might as well surface the actual error
+        if output.stdout.is_empty() {
+            return Err(err);
+        }
+
         warn!(
             "yt-dlp reported errors, trying to parse manifest anyway: {}",
             err
         );
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-
-    Ok(serde_json::from_str(&stdout)?)
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
 }
 
-#[instrument(skip(on_error))]
-fn fetch_track_generic<F>(url: &str, on_error: F) -> Result<TrackStatus>
+#[instrument(skip(source, on_error))]
+fn fetch_track_generic<F>(source: &SourceDefinition, url: &str, on_error: F) -> Result<TrackStatus>
 where
     F: FnOnce(&Output) -> Result<TrackStatus>,
 {
     trace!("fetching track manifest");
 
-    let mut cmd = Command::new("yt-dlp");
+    let mut cmd = source.ytdlp_command();
 
-    cmd.args(["-j", url]);
+    cmd.arg("-j");
+    cmd.args(source.extra_args());
+    cmd.arg(url);
 
     cmd.stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
 
-    let output = cmd.output()?;
+    let output = run_ytdlp(&mut cmd)?;
 
     if !output.status.success() {
+        // a track can require a login even though we are authenticated (e.g.
+        // members-only videos), so it is only recorded as restricted. expired
+        // cookies are reported by yt-dlp explicitly
         match on_error(&output) {
             Ok(status) => return Ok(status),
             Err(err) => {
@@ -139,15 +759,18 @@ where
             // private or deleted, so we should just skip it to prevent the app
             // from retrying indefinitely
             warn!(json = stdout, "failed to parse track manifest: {}", err);
-            return Ok(TrackStatus::NotFound);
+            return Ok(TrackStatus::Unavailable(FailureReason::NotFound));
         }
     };
 
-    Ok(TrackStatus::Available(track))
+    Ok(TrackStatus::Available(Box::new(track)))
 }
 
-#[instrument]
-fn ensure_track_downloaded_generic(track: &Track) -> Result<TrackDownloadStatus> {
+#[instrument(skip(source))]
+fn ensure_track_downloaded_generic(
+    source: &SourceDefinition,
+    track: &Track,
+) -> Result<TrackDownloadStatus> {
     // currently there is no difference between platforms when it comes to
     // actually downloading tracks but we will keep this for when that
     // inevitably changes.
@@ -155,20 +778,36 @@ fn ensure_track_downloaded_generic(track: &Track) -> Result<TrackDownloadStatus>
     trace!("ensuring track is downloaded");
 
     let handle = track.as_handle();
-    if handle.track_path.exists() {
-        return Ok(TrackDownloadStatus::AlreadyDownloaded);
+    // the track may have been downloaded in a different format than the one
+    // that is configured now, in which case we keep the existing file
+    let needs_audio = handle.find_file_ext().is_none();
+
+    if needs_audio {
+        fs::create_dir_all(&handle.root_dir).wrap_err("failed to create track directory")?;
+
+        download_audio(source, track, &handle)?;
     }
 
-    fs::create_dir_all(&handle.root_dir).wrap_err("failed to create track directory")?;
+    if source.archive_video && handle.find_video_ext().is_none() {
+        download_video(source, track, &handle)?;
+    }
 
-    let mut cmd = Command::new("yt-dlp");
+    if needs_audio {
+        Ok(TrackDownloadStatus::Downloaded)
+    } else {
+        Ok(TrackDownloadStatus::AlreadyDownloaded)
+    }
+}
+
+fn download_audio(source: &SourceDefinition, track: &Track, handle: &TrackHandle) -> Result<()> {
+    let mut cmd = source.ytdlp_command();
 
     cmd.args([
         // extract audio (not necessary for SoundCloud but is for YouTube)
         "-x",
-        //
         "--audio-format",
-        "mp3",
+        source.audio_format().ytdlp_format(),
+        // only applies if the audio is re-encoded
         "--audio-quality",
         "0",
         // if the URL contains a reference to a playlist, do NOT download the
@@ -181,30 +820,132 @@ fn ensure_track_downloaded_generic(track: &Track) -> Result<TrackDownloadStatus>
         "sleep",
     ]);
 
-    cmd.arg("-o").arg(&handle.track_path);
+    // the extension depends on the audio format, and is found after the
+    // download
+    cmd.arg("-o").arg(handle.root_dir.join("track.%(ext)s"));
 
     cmd.arg("-o").arg(format!(
         "thumbnail:{}",
         handle.root_dir.join("cover.%(ext)s").display()
     ));
 
+    if source.keep_master() {
+        // keep the downloaded stream after it is converted
+        cmd.arg("-k");
+    }
+
+    if let Some(lyrics) = &source.lyrics {
+        cmd.args(lyrics.ytdlp_args(handle));
+    }
+
+    if let Some(sponsorblock) = &source.sponsorblock {
+        sponsorblock::clear_saved(handle);
+        cmd.args(sponsorblock.ytdlp_args(handle));
+    }
+
+    cmd.args(source.extra_args());
     cmd.arg(&track.url);
 
     cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
 
-    let output = cmd.output()?;
+    let output = run_ytdlp(&mut cmd)?;
 
     if !output.status.success() {
         source_bail!(output);
     }
 
+    if source.keep_master() {
+        keep_master(handle, source.audio_format())?;
+    }
+
     debug!("track downloaded, converting thumbnail to JPG");
 
-    if let Err(err) = convert_thumbnail(&handle) {
+    if let Err(err) = convert_thumbnail(handle) {
         warn!("failed to convert thumbnail to JPG: {}", err);
     }
 
-    Ok(TrackDownloadStatus::Downloaded)
+    Ok(())
+}
+
+/// Downloads the best video (merged with the best audio) of a track to
+/// `video.mkv`.
+fn download_video(source: &SourceDefinition, track: &Track, handle: &TrackHandle) -> Result<()> {
+    debug!("downloading video");
+
+    let mut cmd = source.ytdlp_command();
+
+    cmd.args([
+        "-f",
+        "bestvideo*+bestaudio/best",
+        // formats that don't need to be merged are remuxed instead
+        "--merge-output-format",
+        "mkv",
+        "--remux-video",
+        "mkv",
+        "--no-playlist",
+        "--add-metadata",
+        "-t",
+        "sleep",
+    ]);
+
+    cmd.arg("-o").arg(handle.root_dir.join("video.%(ext)s"));
+
+    cmd.args(source.extra_args());
+    cmd.arg(&track.url);
+
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    let output = run_ytdlp(&mut cmd)?;
+
+    if !output.status.success() {
+        source_bail!(output);
+    }
+
+    Ok(())
+}
+
+/// Renames the stream yt-dlp kept next to the playback file (see `-k`) to
+/// `master.<ext>`.
+///
+/// If the stream did not have to be converted, yt-dlp only produces a single
+/// file, and no separate master is kept.
+fn keep_master(handle: &TrackHandle, format: AudioFormat) -> Result<()> {
+    let exts = handle.find_exts("track");
+
+    let playback = match format.ext() {
+        Some(ext) => Some(ext.to_string()),
+        None => exts
+            .iter()
+            .find(|ext| AUDIO_EXTENSIONS.contains(&ext.as_str()))
+            .cloned(),
+    };
+
+    let Some(playback) = playback.filter(|ext| exts.contains(ext)) else {
+        bail!("could not find the playback file in {:?}", handle.root_dir);
+    };
+
+    for ext in exts.into_iter().filter(|ext| ext != &playback) {
+        trace!("keeping track.{} as the master", ext);
+
+        fs::rename(
+            handle.root_dir.join("track").with_extension(&ext),
+            handle.root_dir.join("master").with_extension(&ext),
+        )
+        .wrap_err("failed to rename master")?;
+    }
+
+    Ok(())
+}
+
+/// Classifies yt-dlp's error output for a track using the error messages shared
+/// between platforms.
+fn check_track_errors_common(output: &Output) -> Result<TrackStatus> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    match FailureReason::classify(&stderr) {
+        Some(reason) => Ok(TrackStatus::Unavailable(reason)),
+        None => source_bail!(stderr),
+    }
 }
 
 fn convert_thumbnail(handle: &TrackHandle) -> Result<()> {
@@ -250,3 +991,118 @@ macro_rules! source_bail {
 }
 
 pub(crate) use source_bail;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: &str) -> Track {
+        Track {
+            id: id.to_string(),
+            uploader: "uploader".to_string(),
+            title: "title".to_string(),
+            url: format!("https://example.com/{id}"),
+            idx: 0,
+            file_ext: None,
+            master_ext: None,
+            video_ext: None,
+            sponsor_segments: None,
+            version: 1,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_resolve_flat_listing() {
+        AppConfig::initialize();
+
+        let source: SourceDefinition =
+            serde_json::from_str(r#"{ "type": "youtube", "url": "https://example.com/flat" }"#)
+                .unwrap();
+
+        let listing = FlatPlaylist::from_manifest(
+            r#"{
+                "_type": "playlist",
+                "id": "flat",
+                "title": "flat",
+                "original_url": "https://example.com/flat",
+                "playlist_count": 4,
+                "entries": [
+                    { "id": "known", "url": "https://example.com/known" },
+                    { "id": "broken", "url": "https://example.com/broken" },
+                    { "id": "private", "url": "https://example.com/private" },
+                    { "id": "new", "url": "https://example.com/new" }
+                ]
+            }"#,
+        )
+        .unwrap()
+        .unwrap();
+
+        let previous = Playlist {
+            id: "flat".to_string(),
+            title: "flat".to_string(),
+            entries: vec![track("known").with_idx(1)],
+            url: source.url.clone(),
+            len: 1,
+            sections: Vec::new(),
+        };
+
+        // a new track that can't be fetched is left out instead of failing
+        // the whole source
+        let playlist = resolve_flat_listing(&source, &[], listing, &previous, |_, url| {
+            match url.rsplit('/').next().unwrap() {
+                "broken" => Err(eyre!("ERROR: something else entirely")),
+                "private" => Ok(TrackStatus::Unavailable(FailureReason::Private)),
+                id => Ok(TrackStatus::Available(Box::new(track(id).with_idx(())))),
+            }
+        });
+
+        assert_eq!(
+            playlist
+                .entries
+                .iter()
+                .map(|t| (t.id.as_str(), t.idx))
+                .collect::<Vec<_>>(),
+            [("known", 1), ("new", 4)]
+        );
+    }
+
+    #[test]
+    fn test_keep_master() {
+        AppConfig::initialize();
+
+        let handle = |id: &str| {
+            let handle = track(id).as_handle();
+
+            let _ = fs::remove_dir_all(&handle.root_dir);
+            fs::create_dir_all(&handle.root_dir).unwrap();
+            handle
+        };
+
+        // converted: the original stream becomes the master
+        let converted = handle("km-converted");
+        fs::write(converted.root_dir.join("track.webm"), "").unwrap();
+        fs::write(converted.root_dir.join("track.mp3"), "").unwrap();
+        keep_master(&converted, AudioFormat::Mp3).unwrap();
+
+        assert_eq!(converted.find_file_ext().as_deref(), Some("mp3"));
+        assert_eq!(converted.find_master_ext().as_deref(), Some("webm"));
+
+        // remuxed without re-encoding
+        let remuxed = handle("km-remuxed");
+        fs::write(remuxed.root_dir.join("track.webm"), "").unwrap();
+        fs::write(remuxed.root_dir.join("track.opus"), "").unwrap();
+        keep_master(&remuxed, AudioFormat::Original).unwrap();
+
+        assert_eq!(remuxed.find_file_ext().as_deref(), Some("opus"));
+        assert_eq!(remuxed.find_master_ext().as_deref(), Some("webm"));
+
+        // already in the target format, so there is no separate master
+        let unconverted = handle("km-unconverted");
+        fs::write(unconverted.root_dir.join("track.m4a"), "").unwrap();
+        keep_master(&unconverted, AudioFormat::M4a).unwrap();
+
+        assert_eq!(unconverted.find_file_ext().as_deref(), Some("m4a"));
+        assert_eq!(unconverted.find_master_ext(), None);
+    }
+}
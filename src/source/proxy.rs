@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use color_eyre::eyre::Result;
+
+use crate::util::Redacted;
+
+use super::SourceDefinition;
+
+/// A proxy that geo-restricted tracks and playlists are retried through.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Proxy {
+    /// The name of this proxy. This is what the index records, so the URL
+    /// (which may contain credentials) never ends up in it.
+    pub name: String,
+    /// The proxy URL, in any format yt-dlp's `--proxy` option accepts.
+    pub url: Redacted<String>,
+}
+
+/// Runs `f` for a track (or a playlist) identified by `id`, retrying through
+/// `proxies` (usually [`super::Fetcher::proxies`]) if it is geo-restricted.
+///
+/// If the track was previously reachable through a proxy, that proxy is tried
+/// first. Otherwise the track is tried without a proxy, then through each of
+/// the proxies in order. `proxied` (which maps IDs to proxy names) is
+/// updated with the proxy that worked, or cleared if no proxy was needed.
+pub fn with_proxy_fallback<T>(
+    source: &SourceDefinition,
+    proxies: &[&Proxy],
+    id: &str,
+    proxied: &mut HashMap<String, String>,
+    mut f: impl FnMut(&SourceDefinition) -> Result<T>,
+    is_geo_blocked: impl Fn(&Result<T>) -> bool,
+) -> Result<T> {
+    if proxies.is_empty() {
+        return f(source);
+    }
+
+    let recorded = proxied
+        .get(id)
+        .and_then(|name| proxies.iter().find(|proxy| &proxy.name == name))
+        .copied();
+
+    let mut candidates = Vec::with_capacity(proxies.len() + 1);
+    candidates.extend(recorded.map(Some));
+    candidates.push(None);
+    candidates.extend(
+        proxies
+            .iter()
+            .filter(|proxy| recorded.is_none_or(|recorded| recorded.name != proxy.name))
+            .map(|proxy| Some(*proxy)),
+    );
+
+    let mut last = None;
+
+    for candidate in candidates {
+        let result = match candidate {
+            Some(proxy) => {
+                debug!("trying {} through proxy {:?}", id, proxy.name);
+                f(&source.via_proxy(proxy))
+            }
+            None => f(source),
+        };
+
+        if is_geo_blocked(&result) {
+            last = Some(result);
+            continue;
+        }
+
+        if result.is_ok() {
+            match candidate {
+                Some(proxy) => {
+                    proxied.insert(id.to_string(), proxy.name.clone());
+                }
+                None => {
+                    proxied.remove(id);
+                }
+            }
+        }
+
+        return result;
+    }
+
+    warn!("{} is geo-restricted through every proxy", id);
+
+    // there is always at least one candidate (no proxy)
+    last.unwrap()
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::Mutex,
+};
+
+use color_eyre::eyre::{Result, eyre};
+use id3::TagLike;
+
+use crate::{
+    model::{FlatEntry, FlatPlaylist, Playlist, Track},
+    util::Redacted,
+};
+
+use super::{
+    Fetcher, SourceDefinition, TrackDownloadStatus, TrackStatus, failure::FailureReason,
+    proxy::Proxy,
+};
+
+/// An in-memory [`Fetcher`] that serves scripted playlists instead of running
+/// yt-dlp, so refreshes can be simulated without the network.
+///
+/// Tracks are available unless they were marked unavailable (geo-restricted
+/// tracks are available through a proxy), and "downloading" a track writes a
+/// file containing only an ID3 tag, which is enough for the metadata markers
+/// to be written to it.
+#[derive(Default)]
+pub struct ScriptedFetcher {
+    /// Whether playlists that were fetched before are fetched as flat
+    /// listings, which only list the ID, title and duration of each track
+    flat: bool,
+    /// Maps source URL to the tracks currently in the playlist
+    playlists: Mutex<HashMap<String, Vec<Track>>>,
+    /// Maps track ID to the reason the track can't be fetched
+    unavailable: Mutex<HashMap<String, FailureReason>>,
+    /// The IDs of tracks that fail to download
+    broken: Mutex<HashSet<String>>,
+    /// The URLs of sources whose playlist is only reachable through a proxy
+    geo_blocked: Mutex<HashSet<String>>,
+    /// The proxies every source is retried through, instead of the ones in
+    /// the config
+    proxies: Vec<Proxy>,
+    /// The IDs of tracks that were downloaded, in the order they were
+    /// downloaded
+    downloads: Mutex<Vec<String>>,
+}
+
+impl ScriptedFetcher {
+    /// A fetcher that resolves playlists that were fetched before from a flat
+    /// listing, like sources with `flat_playlist` enabled do.
+    pub fn flat() -> Self {
+        Self {
+            flat: true,
+            ..Self::default()
+        }
+    }
+
+    /// Adds a proxy that every source is retried through. The proxy's URL is
+    /// never used, since nothing is fetched.
+    pub fn with_proxy(mut self, name: &str) -> Self {
+        self.proxies.push(Proxy {
+            name: name.to_string(),
+            url: Redacted(String::new()),
+        });
+        self
+    }
+
+    /// Sets the tracks of the playlist returned for the source with the given
+    /// URL. The tracks are numbered in the order they are given.
+    pub fn set_playlist(&self, url: &str, tracks: &[&Track]) {
+        let tracks = tracks
+            .iter()
+            .enumerate()
+            .map(|(idx, track)| Track {
+                idx: idx + 1,
+                ..(*track).clone()
+            })
+            .collect();
+
+        self.playlists
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), tracks);
+    }
+
+    /// Makes fetching the track with the given ID report the given reason.
+    pub fn set_unavailable(&self, id: &str, reason: FailureReason) {
+        self.unavailable
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), reason);
+    }
+
+    /// Makes the track with the given ID available again.
+    pub fn set_available(&self, id: &str) {
+        self.unavailable.lock().unwrap().remove(id);
+    }
+
+    /// Makes downloading the track with the given ID fail (or succeed again).
+    pub fn set_broken(&self, id: &str, broken: bool) {
+        let mut tracks = self.broken.lock().unwrap();
+
+        if broken {
+            tracks.insert(id.to_string());
+        } else {
+            tracks.remove(id);
+        }
+    }
+
+    /// Makes the playlist of the source with the given URL only reachable
+    /// through a proxy (or reachable without one again).
+    pub fn set_geo_blocked(&self, url: &str, geo_blocked: bool) {
+        let mut urls = self.geo_blocked.lock().unwrap();
+
+        if geo_blocked {
+            urls.insert(url.to_string());
+        } else {
+            urls.remove(url);
+        }
+    }
+
+    /// Whether the track can be fetched, which geo-restricted tracks can only
+    /// be through a proxy.
+    fn reachable(&self, source: &SourceDefinition, track: &Track) -> bool {
+        source.active_proxy.is_some()
+            || self.unavailable.lock().unwrap().get(&track.id) != Some(&FailureReason::GeoBlocked)
+    }
+
+    /// Returns the IDs of the tracks that were downloaded since the last call.
+    pub fn take_downloads(&self) -> Vec<String> {
+        std::mem::take(&mut self.downloads.lock().unwrap())
+    }
+}
+
+impl Fetcher for ScriptedFetcher {
+    fn fetch_playlist(
+        &self,
+        source: &SourceDefinition,
+        previous: Option<&Playlist>,
+    ) -> Result<Playlist> {
+        if source.active_proxy.is_none() && self.geo_blocked.lock().unwrap().contains(&source.url) {
+            return Err(eyre!(
+                "ERROR: [scripted] This playlist is not available from your location due to geo restriction"
+            ));
+        }
+
+        let entries = self
+            .playlists
+            .lock()
+            .unwrap()
+            .get(&source.url)
+            .cloned()
+            .ok_or_else(|| eyre!("no playlist scripted for {}", source.url))?;
+
+        // the ID is used as a file name
+        let id = source
+            .url
+            .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+
+        let listing = FlatPlaylist {
+            id: id.clone(),
+            title: source.url.clone(),
+            url: source.url.clone(),
+            len: entries.len(),
+            entries: entries
+                .iter()
+                .map(|track| FlatEntry {
+                    id: Some(track.id.clone()),
+                    url: track.url.clone(),
+                    idx: Some(track.idx),
+                    ie_key: None,
+                    title: Some(track.title.clone()),
+                    duration: track.metadata.duration,
+                })
+                .collect(),
+        };
+        let fetch_track = |source: &SourceDefinition, url: &str| {
+            let track = entries
+                .iter()
+                .find(|track| track.url == url)
+                .ok_or_else(|| eyre!("{url} is not part of the playlist"))?;
+
+            self.fetch_track(source, track)
+        };
+
+        if let Some(previous) = previous.filter(|_| self.flat) {
+            return Ok(super::resolve_flat_listing(
+                source,
+                &self.proxies(source),
+                listing,
+                previous,
+                fetch_track,
+            ));
+        }
+
+        // like yt-dlp, the full manifest leaves out geo-restricted tracks
+        let playlist = Playlist {
+            id,
+            title: source.url.clone(),
+            url: source.url.clone(),
+            len: entries.len(),
+            entries: entries
+                .iter()
+                .filter(|track| self.reachable(source, track))
+                .cloned()
+                .collect(),
+            sections: Vec::new(),
+        };
+
+        if self.proxies.is_empty() {
+            return Ok(playlist);
+        }
+
+        Ok(super::resolve_flat_listing(
+            source,
+            &self.proxies(source),
+            listing,
+            &playlist,
+            fetch_track,
+        ))
+    }
+
+    fn fetch_track(&self, source: &SourceDefinition, track: &Track) -> Result<TrackStatus> {
+        if !self.reachable(source, track) {
+            return Ok(TrackStatus::Unavailable(FailureReason::GeoBlocked));
+        }
+
+        Ok(match self.unavailable.lock().unwrap().get(&track.id) {
+            Some(FailureReason::GeoBlocked) | None => {
+                TrackStatus::Available(Box::new(track.clone().with_idx(())))
+            }
+            Some(reason) => TrackStatus::Unavailable(*reason),
+        })
+    }
+
+    fn ensure_track_downloaded(
+        &self,
+        source: &SourceDefinition,
+        track: &Track,
+    ) -> Result<TrackDownloadStatus> {
+        if !self.reachable(source, track) {
+            return Err(eyre!(
+                "ERROR: [scripted] This video is not available from your location due to geo restriction"
+            ));
+        }
+
+        if self.broken.lock().unwrap().contains(&track.id) {
+            return Err(eyre!("scripted download failure"));
+        }
+
+        let handle = track.as_handle();
+
+        if handle.track_path.exists() {
+            return Ok(TrackDownloadStatus::AlreadyDownloaded);
+        }
+
+        fs::create_dir_all(&handle.root_dir)?;
+        fs::write(&handle.track_path, [])?;
+
+        let mut tag = id3::Tag::new();
+        tag.set_title(&track.title);
+        tag.write_to_path(&handle.track_path, id3::Version::Id3v24)?;
+
+        self.downloads.lock().unwrap().push(track.id.clone());
+
+        Ok(TrackDownloadStatus::Downloaded)
+    }
+
+    fn proxies<'a>(&'a self, _source: &'a SourceDefinition) -> Vec<&'a Proxy> {
+        self.proxies.iter().collect()
+    }
+}
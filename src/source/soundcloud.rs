@@ -1,3 +1,5 @@
+use std::process::Output;
+
 use color_eyre::eyre::Result;
 
 use crate::model::{Playlist, Track};
@@ -6,62 +8,127 @@ use super::{Fetcher, SourceDefinition, TrackDownloadStatus, TrackStatus, source_
 
 pub struct SoundCloud;
 
+/// A feed on a SoundCloud user's profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileFeed {
+    /// Every track the user has uploaded
+    Tracks,
+    /// Every track and playlist the user has liked
+    Likes,
+    /// Every track and playlist the user has reposted
+    Reposts,
+}
+
+impl ProfileFeed {
+    /// The path segment of this feed on a profile URL
+    /// (`https://soundcloud.com/<user>/<path>`).
+    pub fn path(&self) -> &'static str {
+        match self {
+            Self::Tracks => "tracks",
+            Self::Likes => "likes",
+            Self::Reposts => "reposts",
+        }
+    }
+
+    /// Converts a profile URL into the URL of this feed. URLs that already
+    /// point to this feed are returned as is (minus any trailing slash).
+    pub fn feed_url(&self, url: &str) -> String {
+        let url = url.trim_end_matches('/');
+        let suffix = format!("/{}", self.path());
+
+        if url.ends_with(&suffix) {
+            url.to_string()
+        } else {
+            format!("{url}{suffix}")
+        }
+    }
+}
+
+/// Archives one of the feeds on a SoundCloud user's profile as a playlist.
+pub struct SoundCloudProfile(pub ProfileFeed);
+
 const GEO_ERR_LINE_1: &str =
     "This video is not available from your location due to geo restriction";
 const GEO_ERR_LINE_2: &str = "You might want to use a VPN or a proxy server";
 
+fn check_playlist_errors(output: &Output) -> Result<()> {
+    // yt-dlp will exit with code 1 if fetching the manifest failed for any
+    // single track, even though it will continue to fetch the rest of the
+    // tracks. so we can't just return an error here. we have to ensure all
+    // lines of stderr are just proxy warnings.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let lines = stderr.lines().collect::<Vec<_>>();
+
+    // check each error and if we find one that is not a proxy warning, throw an
+    // error
+    if !lines.chunks(2).all(|chunk| {
+        chunk.len() == 2 && chunk[0].contains(GEO_ERR_LINE_1) && chunk[1].contains(GEO_ERR_LINE_2)
+    }) {
+        source_bail!(stderr);
+    }
+
+    warn!(
+        "{} songs were not available due to geo restrictions! they will be ignored.",
+        lines.len() / 2
+    );
+
+    Ok(())
+}
+
 impl Fetcher for SoundCloud {
-    fn fetch_playlist(&self, source: &SourceDefinition) -> Result<Playlist> {
-        super::fetch_playlist_generic(&source.url, |output| {
-            // yt-dlp will exit with code 1 if fetching the manifest failed for
-            // any single track, even though it will continue to fetch the rest
-            // of the tracks. so we can't just return an error here. we have to
-            // ensure all lines of stderr are just proxy warnings.
-            let stderr = String::from_utf8_lossy(&output.stderr);
-
-            let lines = stderr.lines().collect::<Vec<_>>();
-
-            // check each error and if we find one that is not a proxy warning,
-            // throw an error
-            if !lines.chunks(2).all(|chunk| {
-                chunk.len() == 2
-                    && chunk[0].contains(GEO_ERR_LINE_1)
-                    && chunk[1].contains(GEO_ERR_LINE_2)
-            }) {
-                source_bail!(stderr);
-            }
-
-            warn!(
-                "{} songs were not available due to geo restrictions! they will be ignored.",
-                lines.len() / 2
-            );
-
-            Ok(())
-        })
+    fn fetch_playlist(
+        &self,
+        source: &SourceDefinition,
+        previous: Option<&Playlist>,
+    ) -> Result<Playlist> {
+        super::fetch_playlist_generic(
+            source,
+            &source.url,
+            previous,
+            check_playlist_errors,
+            |source, url| super::fetch_track_generic(source, url, super::check_track_errors_common),
+        )
     }
 
-    fn fetch_track(&self, track: &Track) -> Result<TrackStatus> {
-        super::fetch_track_generic(&track.url, |output| {
-            let stderr = String::from_utf8_lossy(&output.stderr);
+    fn fetch_track(&self, source: &SourceDefinition, track: &Track) -> Result<TrackStatus> {
+        super::fetch_track_generic(source, &track.url, super::check_track_errors_common)
+    }
 
-            let lines = stderr.lines().collect::<Vec<_>>();
+    fn ensure_track_downloaded(
+        &self,
+        source: &SourceDefinition,
+        track: &Track,
+    ) -> Result<TrackDownloadStatus> {
+        super::ensure_track_downloaded_generic(source, track)
+    }
+}
 
-            if lines.len() == 2
-                && lines[0].contains(GEO_ERR_LINE_1)
-                && lines[1].contains(GEO_ERR_LINE_2)
-            {
-                return Ok(TrackStatus::Restricted);
-            }
+impl Fetcher for SoundCloudProfile {
+    fn fetch_playlist(
+        &self,
+        source: &SourceDefinition,
+        previous: Option<&Playlist>,
+    ) -> Result<Playlist> {
+        let mut playlist = SoundCloud.fetch_playlist(source, previous)?;
 
-            if stderr.contains("HTTP Error 404") {
-                return Ok(TrackStatus::NotFound);
-            }
+        // yt-dlp uses the user's ID as the ID of every feed on their profile,
+        // so we have to disambiguate them or they would share a playlist
+        // definition
+        playlist.id = format!("{}-{}", playlist.id, self.0.path());
+
+        Ok(playlist)
+    }
 
-            source_bail!(stderr)
-        })
+    fn fetch_track(&self, source: &SourceDefinition, track: &Track) -> Result<TrackStatus> {
+        SoundCloud.fetch_track(source, track)
     }
 
-    fn ensure_track_downloaded(&self, track: &Track) -> Result<TrackDownloadStatus> {
-        super::ensure_track_downloaded_generic(track)
+    fn ensure_track_downloaded(
+        &self,
+        source: &SourceDefinition,
+        track: &Track,
+    ) -> Result<TrackDownloadStatus> {
+        SoundCloud.ensure_track_downloaded(source, track)
     }
 }
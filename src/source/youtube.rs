@@ -1,55 +1,372 @@
-use color_eyre::eyre::Result;
+use std::{cell::Cell, collections::HashSet, process::Output};
 
-use crate::model::{Playlist, Track};
+use color_eyre::eyre::{Result, eyre};
+
+use crate::model::{Playlist, PlaylistSection, Track};
 
 use super::{Fetcher, SourceDefinition, TrackDownloadStatus, TrackStatus, source_bail};
 
 pub struct YouTube;
 
+/// Archives the tabs of a YouTube channel as a single playlist.
+pub struct YouTubeChannel;
+
+/// A tab on a YouTube channel's page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelTab {
+    Videos,
+    Shorts,
+    Streams,
+    /// Albums and singles. Each release is a playlist of its own, so the
+    /// releases are flattened into their tracks.
+    Releases,
+}
+
+impl ChannelTab {
+    const ALL: [Self; 4] = [Self::Videos, Self::Shorts, Self::Streams, Self::Releases];
+
+    /// The path segment of this tab on a channel URL
+    /// (`https://www.youtube.com/@<channel>/<path>`).
+    pub fn path(&self) -> &'static str {
+        match self {
+            Self::Videos => "videos",
+            Self::Shorts => "shorts",
+            Self::Streams => "streams",
+            Self::Releases => "releases",
+        }
+    }
+
+    /// Strips any tab suffix from a channel URL.
+    pub fn channel_url(url: &str) -> String {
+        let url = url.trim_end_matches('/');
+
+        Self::ALL
+            .iter()
+            .find_map(|tab| url.strip_suffix(&format!("/{}", tab.path())))
+            .unwrap_or(url)
+            .to_string()
+    }
+}
+
+/// Options for `youtube-channel` sources.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChannelOptions {
+    /// The tabs to archive, in the order their tracks should appear in the
+    /// playlist.
+    #[serde(default = "ChannelOptions::default_tabs")]
+    pub tabs: Vec<ChannelTab>,
+    /// Write a separate playlist definition for each tab, in addition to the
+    /// combined playlist.
+    #[serde(default)]
+    pub per_tab_playlists: bool,
+}
+
+impl ChannelOptions {
+    fn default_tabs() -> Vec<ChannelTab> {
+        vec![ChannelTab::Videos]
+    }
+}
+
+impl Default for ChannelOptions {
+    fn default() -> Self {
+        Self {
+            tabs: Self::default_tabs(),
+            per_tab_playlists: false,
+        }
+    }
+}
+
+fn check_playlist_errors(output: &Output) -> Result<()> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let lines = stderr.lines().collect::<Vec<_>>();
+
+    if !lines.iter().all(|line| line.contains("Video unavailable")) {
+        source_bail!(stderr);
+    }
+
+    warn!(
+        "{} songs were not available for unknown reasons! they will be ignored.",
+        lines.len()
+    );
+
+    Ok(())
+}
+
 impl Fetcher for YouTube {
-    fn fetch_playlist(&self, source: &SourceDefinition) -> Result<Playlist> {
-        super::fetch_playlist_generic(&source.url, |output| {
+    fn fetch_playlist(
+        &self,
+        source: &SourceDefinition,
+        previous: Option<&Playlist>,
+    ) -> Result<Playlist> {
+        super::fetch_playlist_generic(
+            source,
+            &source.url,
+            previous,
+            check_playlist_errors,
+            |source, url| super::fetch_track_generic(source, url, super::check_track_errors_common),
+        )
+    }
+
+    fn fetch_track(&self, source: &SourceDefinition, track: &Track) -> Result<TrackStatus> {
+        super::fetch_track_generic(source, &track.url, super::check_track_errors_common)
+    }
+
+    fn ensure_track_downloaded(
+        &self,
+        source: &SourceDefinition,
+        track: &Track,
+    ) -> Result<TrackDownloadStatus> {
+        super::ensure_track_downloaded_generic(source, track)
+    }
+}
+
+impl YouTubeChannel {
+    /// Fetches a single tab of a channel. Returns `None` if the channel does
+    /// not have this tab.
+    fn fetch_tab(
+        &self,
+        source: &SourceDefinition,
+        tab: ChannelTab,
+        previous: Option<&Playlist>,
+    ) -> Result<Option<Playlist>> {
+        let url = format!("{}/{}", source.url, tab.path());
+        let missing = Cell::new(false);
+
+        let on_error = |output: &Output| {
             let stderr = String::from_utf8_lossy(&output.stderr);
 
-            let lines = stderr.lines().collect::<Vec<_>>();
+            // e.g. "This channel does not have a shorts tab"
+            if stderr.contains("does not have a") {
+                missing.set(true);
+                return Err(eyre!("channel does not have a {} tab", tab.path()));
+            }
+
+            check_playlist_errors(output)
+        };
+
+        let result = match tab {
+            // every release is a playlist, which flat listings can't handle
+            ChannelTab::Releases => super::fetch_playlist_full(source, &url, on_error),
+            _ => super::fetch_playlist_generic(source, &url, previous, on_error, |source, url| {
+                super::fetch_track_generic(source, url, super::check_track_errors_common)
+            }),
+        };
 
-            if !lines.iter().all(|line| line.contains("Video unavailable")) {
-                source_bail!(stderr);
+        match result {
+            Ok(playlist) => Ok(Some(playlist)),
+            Err(_) if missing.get() => {
+                info!("channel does not have a {} tab, skipping", tab.path());
+                Ok(None)
             }
+            Err(err) => Err(err),
+        }
+    }
+}
 
-            warn!(
-                "{} songs were not available for unknown reasons! they will be ignored.",
-                lines.len()
-            );
+impl Fetcher for YouTubeChannel {
+    fn fetch_playlist(
+        &self,
+        source: &SourceDefinition,
+        previous: Option<&Playlist>,
+    ) -> Result<Playlist> {
+        let mut tabs = Vec::new();
+
+        for &tab in &source.channel.tabs {
+            if let Some(playlist) = self.fetch_tab(source, tab, previous)? {
+                tabs.push((tab, playlist));
+            }
+        }
 
-            Ok(())
-        })
+        combine_tabs(&source.url, &source.channel, tabs)
     }
 
-    fn fetch_track(&self, track: &Track) -> color_eyre::eyre::Result<TrackStatus> {
-        super::fetch_track_generic(&track.url, |output| {
-            let stderr = String::from_utf8_lossy(&output.stderr);
+    fn fetch_track(&self, source: &SourceDefinition, track: &Track) -> Result<TrackStatus> {
+        YouTube.fetch_track(source, track)
+    }
 
-            let lines = stderr.lines().collect::<Vec<_>>();
+    fn ensure_track_downloaded(
+        &self,
+        source: &SourceDefinition,
+        track: &Track,
+    ) -> Result<TrackDownloadStatus> {
+        YouTube.ensure_track_downloaded(source, track)
+    }
+}
 
-            if lines.len() == 1
-                && (lines[0].contains("Video unavailable") || lines[0].contains("Private video"))
-            {
-                return Ok(TrackStatus::Restricted);
-            }
+/// Combines the fetched tabs of a channel into a single playlist, in the order
+/// they were given. Tracks that are part of several tabs are only added once.
+fn combine_tabs(
+    url: &str,
+    options: &ChannelOptions,
+    tabs: Vec<(ChannelTab, Playlist)>,
+) -> Result<Playlist> {
+    let mut id = None;
+    let mut title = None;
+    let mut entries = Vec::new();
+    let mut sections = Vec::new();
+    let mut seen = HashSet::new();
 
-            // giving youtube an invalid URL does not produce a 404, but this is
-            // here in case that behavior changes. currently all invalid URLs
-            // produce the "Video unavailable" error above.
-            if stderr.contains("HTTP Error 404") {
-                return Ok(TrackStatus::NotFound);
+    for (tab, playlist) in tabs {
+        // every tab has the channel's ID, and a title like "<channel> -
+        // Videos"
+        let channel_title = playlist
+            .title
+            .rsplit_once(" - ")
+            .map(|(channel, _)| channel.to_string())
+            .unwrap_or_else(|| playlist.title.clone());
+
+        let channel_id = id.get_or_insert(playlist.id);
+        title.get_or_insert(channel_title);
+
+        let mut sorted = playlist.entries;
+        sorted.sort_by_key(|t| t.idx);
+
+        let mut section = PlaylistSection {
+            id: format!("{}-{}", channel_id, tab.path()),
+            title: playlist.title,
+            track_ids: Vec::with_capacity(sorted.len()),
+        };
+
+        for mut track in sorted {
+            section.track_ids.push(track.id.clone());
+
+            if seen.insert(track.id.clone()) {
+                track.idx = entries.len() + 1;
+                entries.push(track);
             }
+        }
 
-            source_bail!(stderr)
-        })
+        if options.per_tab_playlists {
+            sections.push(section);
+        }
     }
 
-    fn ensure_track_downloaded(&self, track: &Track) -> Result<TrackDownloadStatus> {
-        super::ensure_track_downloaded_generic(track)
+    let id = id.ok_or_else(|| eyre!("none of the configured channel tabs exist"))?;
+
+    Ok(Playlist {
+        title: title.unwrap_or_else(|| id.clone()),
+        id,
+        url: url.to_string(),
+        len: entries.len(),
+        entries,
+        sections,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tab_manifest(title: &str, entries: &str) -> Playlist {
+        Playlist::from_manifest(&format!(
+            r#"{{
+                "_type": "playlist",
+                "id": "UC1234",
+                "title": "{title}",
+                "original_url": "https://www.youtube.com/@channel",
+                "playlist_count": 0,
+                "entries": [{entries}]
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    fn entry(id: &str, idx: usize) -> String {
+        format!(
+            r#"{{ "id": "{id}", "uploader": "channel", "title": "{id}",
+                "original_url": "https://www.youtube.com/watch?v={id}", "playlist_index": {idx} }}"#
+        )
+    }
+
+    #[test]
+    fn test_channel_url() {
+        for url in [
+            "https://www.youtube.com/@channel",
+            "https://www.youtube.com/@channel/",
+            "https://www.youtube.com/@channel/videos",
+            "https://www.youtube.com/@channel/releases/",
+        ] {
+            assert_eq!(
+                ChannelTab::channel_url(url),
+                "https://www.youtube.com/@channel"
+            );
+        }
+    }
+
+    #[test]
+    fn test_channel_options() {
+        let options = serde_json::from_str::<ChannelOptions>("{}").unwrap();
+        assert_eq!(options.tabs, [ChannelTab::Videos]);
+        assert!(!options.per_tab_playlists);
+
+        let options = serde_json::from_str::<ChannelOptions>(
+            r#"{ "tabs": ["releases", "videos"], "per_tab_playlists": true }"#,
+        )
+        .unwrap();
+        assert_eq!(options.tabs, [ChannelTab::Releases, ChannelTab::Videos]);
+        assert!(options.per_tab_playlists);
+    }
+
+    #[test]
+    fn test_combine_tabs() {
+        let tabs = || {
+            let videos = tab_manifest(
+                "Channel - Videos",
+                &[entry("b", 2), entry("a", 1)].join(","),
+            );
+            // every release is a playlist of its own
+            let releases = tab_manifest(
+                "Channel - Releases",
+                &format!(
+                    r#"{{ "_type": "playlist", "id": "album", "title": "album",
+                        "playlist_index": 1, "entries": [{}, {}] }}"#,
+                    entry("c", 1),
+                    entry("a", 2)
+                ),
+            );
+
+            vec![
+                (ChannelTab::Videos, videos),
+                (ChannelTab::Releases, releases),
+            ]
+        };
+
+        let options = ChannelOptions {
+            tabs: vec![ChannelTab::Videos, ChannelTab::Releases],
+            per_tab_playlists: false,
+        };
+        let playlist = combine_tabs("https://www.youtube.com/@channel", &options, tabs()).unwrap();
+
+        assert_eq!(playlist.id, "UC1234");
+        assert_eq!(playlist.title, "Channel");
+        assert_eq!(
+            playlist
+                .entries
+                .iter()
+                .map(|t| (t.id.as_str(), t.idx))
+                .collect::<Vec<_>>(),
+            [("a", 1), ("b", 2), ("c", 3)]
+        );
+        assert!(playlist.sections.is_empty());
+
+        // each tab gets a section with every track in it, even the ones that
+        // are part of an earlier tab
+        let options = ChannelOptions {
+            per_tab_playlists: true,
+            ..options
+        };
+        let playlist = combine_tabs("https://www.youtube.com/@channel", &options, tabs()).unwrap();
+
+        assert_eq!(playlist.sections.len(), 2);
+        assert_eq!(playlist.sections[0].id, "UC1234-videos");
+        assert_eq!(playlist.sections[0].track_ids, ["a", "b"]);
+        assert_eq!(playlist.sections[1].id, "UC1234-releases");
+        assert_eq!(playlist.sections[1].title, "Channel - Releases");
+        assert_eq!(playlist.sections[1].track_ids, ["c", "a"]);
+
+        // a channel without any of the configured tabs
+        assert!(combine_tabs("https://www.youtube.com/@channel", &options, Vec::new()).is_err());
     }
 }
@@ -0,0 +1,151 @@
+use std::fs;
+
+use crate::model::TrackHandle;
+
+/// The file yt-dlp writes the SponsorBlock segments of a track to.
+const SEGMENTS_FILE: &str = "sponsorblock.json";
+
+/// Options for applying SponsorBlock segments to tracks from a source. The
+/// categories are SponsorBlock categories (e.g. `sponsor`, `intro`,
+/// `music_offtopic`), see yt-dlp's `--sponsorblock-mark` option.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SponsorBlockOptions {
+    /// The categories of segments that are cut out of the track.
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// The categories of segments that are marked as chapters.
+    #[serde(default)]
+    pub mark: Vec<String>,
+}
+
+impl SponsorBlockOptions {
+    /// The yt-dlp arguments that apply the segments and save the segments
+    /// that were found next to the track.
+    pub fn ytdlp_args(&self, handle: &TrackHandle) -> Vec<String> {
+        let mut args = Vec::new();
+
+        if !self.remove.is_empty() {
+            args.extend(["--sponsorblock-remove".to_string(), self.remove.join(",")]);
+        }
+
+        if !self.mark.is_empty() {
+            args.extend(["--sponsorblock-mark".to_string(), self.mark.join(",")]);
+        }
+
+        args.extend([
+            "--print-to-file".to_string(),
+            "after_move:%(sponsorblock_chapters)j".to_string(),
+            handle.root_dir.join(SEGMENTS_FILE).display().to_string(),
+        ]);
+
+        args
+    }
+}
+
+/// A SponsorBlock segment that was applied to a track.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AppliedSegment {
+    pub category: String,
+    /// The start of the segment in seconds, relative to the original track
+    pub start: f64,
+    /// The end of the segment in seconds, relative to the original track
+    pub end: f64,
+    /// Whether the segment was cut out of the track (otherwise it was only
+    /// marked as a chapter)
+    pub removed: bool,
+}
+
+/// A segment as yt-dlp prints it.
+#[derive(Deserialize)]
+struct Segment {
+    category: String,
+    start_time: f64,
+    end_time: f64,
+}
+
+/// Deletes the segments saved for a track, since yt-dlp appends to the file.
+pub fn clear_saved(handle: &TrackHandle) {
+    let _ = fs::remove_file(handle.root_dir.join(SEGMENTS_FILE));
+}
+
+/// Reads the segments saved next to a track. Returns `None` if no segments
+/// were saved (e.g. because SponsorBlock is not enabled for the source).
+pub fn read_saved(
+    handle: &TrackHandle,
+    options: &SponsorBlockOptions,
+) -> Option<Vec<AppliedSegment>> {
+    let saved = fs::read_to_string(handle.root_dir.join(SEGMENTS_FILE)).ok()?;
+
+    // yt-dlp prints `NA` if the track has no segments
+    let segments = match serde_json::from_str::<Vec<Segment>>(saved.trim()) {
+        Ok(segments) => segments,
+        Err(_) => {
+            debug!("no sponsorblock segments were saved: {:?}", saved.trim());
+            return Some(Vec::new());
+        }
+    };
+
+    Some(
+        segments
+            .into_iter()
+            .map(|segment| AppliedSegment {
+                removed: options
+                    .remove
+                    .iter()
+                    .any(|category| category == "all" || category == &segment.category),
+                category: segment.category,
+                start: segment.start_time,
+                end: segment.end_time,
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_read_saved() {
+        let root_dir = PathBuf::from("/tmp/ACAD_TESTS/sponsorblock");
+        fs::create_dir_all(&root_dir).unwrap();
+
+        let handle = TrackHandle {
+            track_path: root_dir.join("track.mp3"),
+            master_path: None,
+            video_path: None,
+            album_art_path: root_dir.join("cover.jpg"),
+            root_dir,
+        };
+
+        let options = SponsorBlockOptions {
+            remove: vec!["sponsor".to_string()],
+            mark: vec!["intro".to_string()],
+        };
+
+        clear_saved(&handle);
+        assert_eq!(read_saved(&handle, &options), None);
+
+        fs::write(
+            handle.root_dir.join(SEGMENTS_FILE),
+            r#"[
+                {"start_time": 0.0, "end_time": 12.5, "category": "intro", "type": "skip"},
+                {"start_time": 60.0, "end_time": 90.0, "category": "sponsor", "type": "skip"}
+            ]"#,
+        )
+        .unwrap();
+
+        let segments = read_saved(&handle, &options).unwrap();
+
+        assert_eq!(segments.len(), 2);
+        assert!(!segments[0].removed);
+        assert_eq!(segments[1].category, "sponsor");
+        assert_eq!(segments[1].end, 90.0);
+        assert!(segments[1].removed);
+
+        fs::write(handle.root_dir.join(SEGMENTS_FILE), "NA\n").unwrap();
+        assert_eq!(read_saved(&handle, &options), Some(Vec::new()));
+    }
+}
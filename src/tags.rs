@@ -0,0 +1,101 @@
+use color_eyre::eyre::{Context, Result};
+use id3::{Frame, TagLike, Timestamp, frame};
+
+use crate::model::{Track, TrackHandle, TrackMetadata};
+
+/// Writes the metadata from a track's manifest to its ID3 tag. yt-dlp already
+/// writes most of the basic metadata when it downloads the track, so frames
+/// that yt-dlp wrote are left alone.
+pub fn write_tags(handle: &TrackHandle, track: &Track) -> Result<()> {
+    let mut tag = id3::Tag::read_from_path(&handle.track_path)
+        .wrap_err("failed to read track metadata to write tags to")?;
+
+    let TrackMetadata {
+        upload_date,
+        duration,
+        genre,
+        tags,
+        uploader_id,
+        uploader_url,
+        license,
+        ..
+    } = &*track.metadata;
+
+    if let Some(genre) = genre
+        && tag.genre().is_none()
+    {
+        tag.set_genre(genre);
+    }
+
+    if let Some(date) = upload_date.as_deref().and_then(parse_upload_date)
+        && tag.date_recorded().is_none()
+    {
+        tag.set_date_recorded(date);
+    }
+
+    if let Some(duration) = duration
+        && tag.duration().is_none()
+    {
+        tag.set_duration((duration * 1000.0) as u32);
+    }
+
+    // the page of the track on the platform
+    tag.add_frame(Frame::link("WOAS", &track.url));
+
+    if let Some(uploader_url) = uploader_url {
+        tag.add_frame(Frame::link("WOAR", uploader_url));
+    }
+
+    let extended = [
+        ("uploader_id", uploader_id.clone()),
+        ("license", license.clone()),
+        ("tags", tags.as_ref().map(|tags| tags.join("; "))),
+    ];
+
+    for (description, value) in extended {
+        if let Some(value) = value {
+            tag.add_frame(frame::ExtendedText {
+                description: description.to_string(),
+                value,
+            });
+        }
+    }
+
+    tag.write_to_path(&handle.track_path, id3::Version::Id3v24)
+        .wrap_err("failed to write tags")?;
+
+    Ok(())
+}
+
+/// Parses yt-dlp's `YYYYMMDD` upload dates.
+fn parse_upload_date(date: &str) -> Option<Timestamp> {
+    if date.len() != 8 || !date.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(Timestamp {
+        year: date[0..4].parse().ok()?,
+        month: Some(date[4..6].parse().ok()?),
+        day: Some(date[6..8].parse().ok()?),
+        hour: None,
+        minute: None,
+        second: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_upload_date() {
+        let date = parse_upload_date("20240131").unwrap();
+
+        assert_eq!(date.year, 2024);
+        assert_eq!(date.month, Some(1));
+        assert_eq!(date.day, Some(31));
+
+        assert!(parse_upload_date("2024-01-31").is_none());
+        assert!(parse_upload_date("").is_none());
+    }
+}
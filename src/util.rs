@@ -1,3 +1,38 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Write},
+    ops::Deref,
+    path::Path,
+    process::{Command, Output, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Wraps a value that must not show up in logs (e.g. the path to a cookies
+/// file). The `Debug` implementation prints `<redacted>` instead of the value.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Redacted<T>(pub T);
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl<T> Deref for Redacted<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
 /// Compute the changes between two `Vec`s. Returns a tuple of two vectors, the
 /// first containing the items that were added, the second containing the items
 /// that were removed.
@@ -13,7 +48,7 @@
 /// assert_eq!(removed, vec!["old"]);
 /// ```
 #[inline]
-pub fn diff_with<'a, T, F>(old: &'a Vec<T>, new: &'a Vec<T>, cmp: F) -> (Vec<&'a T>, Vec<&'a T>)
+pub fn diff_with<'a, 'b, T, F>(old: &'a Vec<T>, new: &'b Vec<T>, cmp: F) -> (Vec<&'b T>, Vec<&'a T>)
 where
     F: Fn(&T, &T) -> bool,
 {
@@ -43,10 +78,16 @@ where
     (added, removed)
 }
 
+/// Like [`diff_with`], but the new items are given by reference and may be of a
+/// different type than the old items.
 #[inline]
-pub fn diff_ref_with<'a, T, F>(old: &'a Vec<T>, new: Vec<&'a T>, cmp: F) -> (Vec<&'a T>, Vec<&'a T>)
+pub fn diff_ref_with<'a, T, U, F>(
+    old: &'a Vec<T>,
+    new: Vec<&'a U>,
+    cmp: F,
+) -> (Vec<&'a U>, Vec<&'a T>)
 where
-    F: Fn(&T, &T) -> bool,
+    F: Fn(&U, &T) -> bool,
 {
     let mut added = Vec::new();
     let mut removed = Vec::new();
@@ -58,7 +99,7 @@ where
     }
 
     for item in new {
-        if !old.iter().any(|i| cmp(i, item)) {
+        if !old.iter().any(|i| cmp(item, i)) {
             added.push(item);
         }
     }
@@ -66,8 +107,130 @@ where
     (added, removed)
 }
 
+/// Calls `f` on every item using up to `workers` threads. `on_result` is
+/// called on the calling thread with the index of each item and its result as
+/// soon as the item is done, so results arrive in the order they finished.
+pub fn parallel_for_each<T, R, F, G>(items: &[T], workers: usize, f: F, mut on_result: G)
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+    G: FnMut(usize, R),
+{
+    let workers = workers.clamp(1, items.len().max(1));
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            let tx = tx.clone();
+            let (f, next) = (&f, &next);
+
+            scope.spawn(move || {
+                loop {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(idx) else {
+                        break;
+                    };
+
+                    // the receiver outlives the workers
+                    let _ = tx.send((idx, f(item)));
+                }
+            });
+        }
+
+        // the channel closes once every worker is done
+        drop(tx);
+
+        for (idx, result) in rx {
+            on_result(idx, result);
+        }
+    });
+}
+
+/// Runs a command to completion and collects its output like
+/// [`Command::output`], but kills it as soon as `abort` returns `true`, in
+/// which case an [`io::ErrorKind::Interrupted`] error is returned.
+pub fn output_unless(cmd: &mut Command, abort: impl Fn() -> bool) -> io::Result<Output> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // the pipes are drained on their own threads, so the child never blocks
+    // on a full pipe while we wait for it
+    let stdout = child.stdout.take().map(drain);
+    let stderr = child.stderr.take().map(drain);
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if abort() {
+            child.kill()?;
+            child.wait()?;
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "the command was killed",
+            ));
+        }
+
+        thread::sleep(Duration::from_millis(20));
+    };
+
+    let join = |pipe: Option<JoinHandle<Vec<u8>>>| {
+        pipe.map(|pipe| pipe.join().unwrap_or_default())
+            .unwrap_or_default()
+    };
+
+    Ok(Output {
+        status,
+        stdout: join(stdout),
+        stderr: join(stderr),
+    })
+}
+
+fn drain(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// Replaces the file at `path` with `contents` without ever leaving a partially
+/// written file behind: the contents are written to a temporary file next to
+/// it, flushed to disk and then renamed over the original file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&temp_path, path)?;
+
+    // the rename itself is only durable once the directory is flushed
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{
+        io,
+        process::Command,
+        time::{Duration, Instant},
+    };
+
     use crate::util;
 
     #[test]
@@ -92,4 +255,42 @@ mod tests {
         assert_eq!(added, vec![&"new1", &"new2"]);
         assert_eq!(removed, vec![&"old1", &"old2"]);
     }
+
+    #[test]
+    fn parallel_for_each() {
+        let items = (0..100u64).collect::<Vec<_>>();
+        let mut results = vec![None; items.len()];
+
+        util::parallel_for_each(
+            &items,
+            8,
+            |i| {
+                // make later items finish first
+                std::thread::sleep(std::time::Duration::from_micros(100 - i));
+                i * 2
+            },
+            |idx, result| {
+                assert!(results[idx].replace(result).is_none());
+            },
+        );
+
+        assert_eq!(
+            results,
+            items.iter().map(|i| Some(i * 2)).collect::<Vec<_>>()
+        );
+
+        util::parallel_for_each(&[] as &[u64], 8, |i| *i, |_, _| panic!("no items"));
+    }
+
+    #[test]
+    fn output_unless() {
+        let output = util::output_unless(Command::new("echo").arg("done"), || false).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"done\n");
+
+        let started = Instant::now();
+        let err = util::output_unless(Command::new("sleep").arg("10"), || true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
 }